@@ -1,6 +1,9 @@
-pub use crate::bytecode::{ArithOp, Cond, Op, PopMode};
+pub use crate::bytecode::{ArithOp, Cond, Constant, Op, PopMode, UnaryOp};
+use crate::bytecode::Program;
+use crate::error::{Error, Result};
+use crate::parser::escape;
 use log::debug;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 pub trait Disass {
     fn print_lines(&self, out: &mut dyn std::io::Write);
@@ -8,7 +11,7 @@ pub trait Disass {
 
 #[derive(Debug)]
 pub enum Section {
-    Data(Vec<i64>),
+    Data(Vec<Constant>),
     Code(Vec<Stmt>),
 }
 impl Disass for Section {
@@ -33,24 +36,65 @@ impl Disass for Section {
 #[derive(Debug, PartialEq)]
 pub enum Stmt {
     PushInline(i64),
+    PushBool(bool),
+    /// Push a string constant, which the assembler adds to the constant section.
+    PushString(String),
     PushConst(i64),
     PushStack(i64),
     Call(String),
     Jmp(Cond, Option<String>),
     Arith(ArithOp),
+    Unary(UnaryOp),
     Output(i64),
     Pop(i64),
     Move(i64),
     Label(String),
     Noop,
+    Ret,
+    LoadLocal(i64),
+    StoreLocal(i64),
+    Concat,
+    Len,
+    /// Create an array from the given number of values on the stack.
+    NewArray(i64),
+    LoadIndex,
+    StoreIndex,
+    /// Create a record from the given number of field values on the stack.
+    NewRecord(i64),
+    LoadField(i64),
+    StoreField(i64),
+    /// Create a function value for the code at the label, capturing the given number of values on
+    /// the stack.
+    Closure(String, i64),
+    /// Call the function value on top of the stack.
+    CallIndirect,
+    LoadGlobal(i64),
+    StoreGlobal(i64),
 }
 impl Disass for Stmt {
     fn print_lines(&self, out: &mut dyn std::io::Write) {
         match self {
             Stmt::PushInline(v) => writeln!(out, "    push {}", v),
+            Stmt::PushBool(v) => writeln!(out, "    push {}", v),
+            Stmt::PushString(s) => writeln!(out, "    push {}", escape(s)),
+            Stmt::Concat => writeln!(out, "    concat"),
+            Stmt::Len => writeln!(out, "    len"),
+            Stmt::NewArray(n) => writeln!(out, "    newarray {}", n),
+            Stmt::LoadIndex => writeln!(out, "    loadidx"),
+            Stmt::StoreIndex => writeln!(out, "    storeidx"),
+            Stmt::NewRecord(n) => writeln!(out, "    newrecord {}", n),
+            Stmt::LoadField(offset) => writeln!(out, "    loadfield {}", offset),
+            Stmt::StoreField(offset) => writeln!(out, "    storefield {}", offset),
             Stmt::PushConst(v) => writeln!(out, "    push const.{}", v),
             Stmt::PushStack(v) => writeln!(out, "    push stack.{}", v),
             Stmt::Call(label) => writeln!(out, "    call {}", label),
+            Stmt::Closure(label, n) => writeln!(out, "    closure {} {}", label, n),
+            Stmt::CallIndirect => writeln!(out, "    calls"),
+            Stmt::Ret => writeln!(out, "    ret"),
+            Stmt::LoadLocal(slot) => writeln!(out, "    load {}", slot),
+            Stmt::StoreLocal(slot) => writeln!(out, "    store {}", slot),
+            Stmt::LoadGlobal(slot) => writeln!(out, "    loadg {}", slot),
+            Stmt::StoreGlobal(slot) => writeln!(out, "    storeg {}", slot),
             Stmt::Jmp(cond, label) => {
                 let cond = match cond {
                     Cond::Always => "always",
@@ -66,12 +110,24 @@ impl Disass for Stmt {
             Stmt::Arith(ArithOp::Sub) => writeln!(out, "    sub"),
             Stmt::Arith(ArithOp::Mul) => writeln!(out, "    mul"),
             Stmt::Arith(ArithOp::Div) => writeln!(out, "    div"),
+            Stmt::Arith(ArithOp::Mod) => writeln!(out, "    mod"),
+            Stmt::Arith(ArithOp::BitAnd) => writeln!(out, "    band"),
+            Stmt::Arith(ArithOp::BitOr) => writeln!(out, "    bor"),
+            Stmt::Arith(ArithOp::BitXor) => writeln!(out, "    bxor"),
+            Stmt::Arith(ArithOp::Shl) => writeln!(out, "    shl"),
+            Stmt::Arith(ArithOp::Shr) => writeln!(out, "    shr"),
+            Stmt::Arith(ArithOp::Ushr) => writeln!(out, "    ushr"),
             Stmt::Arith(ArithOp::Or) => writeln!(out, "    or"),
             Stmt::Arith(ArithOp::And) => writeln!(out, "    and"),
             Stmt::Arith(ArithOp::Equal) => writeln!(out, "    eq"),
             Stmt::Arith(ArithOp::NotEqual) => writeln!(out, "    neq"),
             Stmt::Arith(ArithOp::LessThan) => writeln!(out, "    lt"),
             Stmt::Arith(ArithOp::LessEqual) => writeln!(out, "    le"),
+            Stmt::Arith(ArithOp::GreaterThan) => writeln!(out, "    gt"),
+            Stmt::Arith(ArithOp::GreaterEqual) => writeln!(out, "    ge"),
+            Stmt::Unary(UnaryOp::Neg) => writeln!(out, "    neg"),
+            Stmt::Unary(UnaryOp::Not) => writeln!(out, "    not"),
+            Stmt::Unary(UnaryOp::Complement) => writeln!(out, "    compl"),
             Stmt::Output(channel) => writeln!(out, "    output #{}", channel),
             Stmt::Pop(num) if *num == 1 => writeln!(out, "    pop"),
             Stmt::Pop(num) => writeln!(out, "    pop {}", num),
@@ -85,107 +141,159 @@ impl Disass for Stmt {
 
 pub trait BytecodeEmit {
     fn num_ops(&self) -> usize;
-    fn emit(&self, labels: &HashMap<String, usize>, consts: &Vec<i64>, out: &mut Vec<Op>);
+    fn emit(
+        &self,
+        labels: &HashMap<String, usize>,
+        consts: &Vec<Constant>,
+        out: &mut Vec<Op>,
+    ) -> Result<()>;
 }
 
 impl BytecodeEmit for Stmt {
     fn num_ops(&self) -> usize {
         match self {
             Stmt::Label(_) => 0,
-            Stmt::Arith(_) | Stmt::Output(_) | Stmt::Noop => 1,
-            Stmt::PushInline(n) if *n <= 0xFFFFFF => 1,
+            Stmt::Arith(_) | Stmt::Unary(_) | Stmt::Output(_) | Stmt::Noop | Stmt::Ret => 1,
+            Stmt::CallIndirect => 1,
+            Stmt::LoadLocal(_) | Stmt::StoreLocal(_) | Stmt::PushBool(_) => 1,
+            Stmt::LoadGlobal(_) | Stmt::StoreGlobal(_) => 1,
+            Stmt::Concat | Stmt::Len | Stmt::LoadIndex | Stmt::StoreIndex => 1,
+            Stmt::PushInline(n) if is_inline(*n) => 1,
             Stmt::PushInline(_) => 2,
-            Stmt::Call(_) => 5,
             Stmt::Pop(n) if *n == 0 => 0,
             Stmt::Pop(n) if *n == 1 => 1,
-            Stmt::Move(_) | Stmt::PushConst(_) | Stmt::PushStack(_) => 2,
-            Stmt::Jmp(_, Some(_)) | Stmt::Pop(_) => 2,
+            Stmt::Move(_) | Stmt::PushConst(_) | Stmt::PushStack(_) | Stmt::PushString(_) => 2,
+            Stmt::NewArray(_) | Stmt::NewRecord(_) => 2,
+            Stmt::LoadField(_) | Stmt::StoreField(_) => 1,
+            Stmt::Call(_) | Stmt::Closure(..) | Stmt::Jmp(_, Some(_)) | Stmt::Pop(_) => 2,
             Stmt::Jmp(_, None) => 3,
         }
     }
-    fn emit(&self, labels: &HashMap<String, usize>, consts: &Vec<i64>, out: &mut Vec<Op>) {
+    fn emit(
+        &self,
+        labels: &HashMap<String, usize>,
+        consts: &Vec<Constant>,
+        out: &mut Vec<Op>,
+    ) -> Result<()> {
         match self {
-            Stmt::PushInline(v) if *v <= 0x7FFF => out.push(Op::PushImmediate(*v as i16)),
-            Stmt::PushInline(v) if *v <= 0xFFFFFF => {
+            Stmt::PushInline(v) if *v >= i16::MIN as i64 && *v <= 0x7FFF => {
+                out.push(Op::PushImmediate(*v as i16))
+            }
+            Stmt::PushInline(v) if is_inline(*v) => {
                 out.push(Op::PushImmediate24((*v as u32).into()))
             }
             Stmt::PushInline(v) => {
                 let i = consts
                     .iter()
-                    .position(|x| x == v)
-                    .expect("missing const for large value");
-                if i > 0x7FFF {
-                    panic!("TODO: pop n > 0x7FFF not implemented"); // support 24bit
-                }
-                out.push(Op::PushImmediate(i as i16));
+                    .position(|x| *x == Constant::Int(*v))
+                    .ok_or_else(|| {
+                        Error::Assemble(format!("missing const for large value {}", v))
+                    })?;
+                out.push(Op::PushImmediate(immediate(i as i64, "const index")?)); // TODO: support 24bit
+                out.push(Op::PushConst);
+            }
+            Stmt::PushBool(v) => out.push(Op::PushBool(*v)),
+            Stmt::PushString(s) => {
+                let i = consts
+                    .iter()
+                    .position(|x| matches!(x, Constant::Str(x) if x == s))
+                    .ok_or_else(|| Error::Assemble(format!("missing const for string {:?}", s)))?;
+                out.push(Op::PushImmediate(immediate(i as i64, "const index")?)); // TODO: support 24bit
                 out.push(Op::PushConst);
             }
             Stmt::PushConst(i) => {
-                if *i > 0x7FFF {
-                    panic!("TODO: push const n > 0x7FFF not implemented"); // support 24bit
-                }
-                out.push(Op::PushImmediate(*i as i16));
+                out.push(Op::PushImmediate(immediate(*i, "const index")?)); // TODO: support 24bit
                 out.push(Op::PushConst);
             }
             Stmt::PushStack(i) => {
-                if *i > 0x7FFF {
-                    panic!("TODO: push stack n > 0x7FFF not implemented"); // support 24bit
-                }
-                out.push(Op::PushImmediate(*i as i16));
+                out.push(Op::PushImmediate(immediate(*i, "stack offset")?)); // TODO: support 24bit
                 out.push(Op::PushStack);
             }
             Stmt::Call(label) => {
-                out.push(Op::PushIp);
-                out.push(Op::PushImmediate(5));
-                out.push(Op::Arith(ArithOp::Add));
-
-                let rel_addr = *labels.get(label).unwrap() as i64 - out.len() as i64;
-                if rel_addr > 0x7FFF {
-                    // FIXME: negative values!
-                    panic!("TODO: jmp n > 0x7FFF not implemented"); // support 24bit / const
-                }
-                out.push(Op::PushImmediate((rel_addr - 1) as i16));
-                out.push(Op::Jmp(Cond::Always));
+                let rel_addr = label_location(labels, label)? as i64 - out.len() as i64;
+                out.push(Op::PushImmediate(immediate(rel_addr - 1, "call offset")?)); // TODO: support 24bit / const
+                out.push(Op::Call);
+            }
+            Stmt::Closure(label, n) => {
+                let rel_addr = label_location(labels, label)? as i64 - out.len() as i64;
+                out.push(Op::PushImmediate(immediate(
+                    rel_addr - 1,
+                    "closure offset",
+                )?)); // TODO: support 24bit / const
+                out.push(Op::MakeClosure(immediate(*n, "capture count")?));
             }
+            Stmt::CallIndirect => out.push(Op::CallIndirect),
+            Stmt::Ret => out.push(Op::Ret),
+            Stmt::LoadLocal(slot) => out.push(Op::LoadLocal(immediate(*slot, "local slot")?)),
+            Stmt::StoreLocal(slot) => out.push(Op::StoreLocal(immediate(*slot, "local slot")?)),
+            Stmt::LoadGlobal(slot) => out.push(Op::LoadGlobal(immediate(*slot, "global slot")?)),
+            Stmt::StoreGlobal(slot) => out.push(Op::StoreGlobal(immediate(*slot, "global slot")?)),
             Stmt::Jmp(cond, Some(label)) => {
-                let rel_addr = *labels.get(label).unwrap() as i64 - out.len() as i64;
-                if rel_addr > 0x7FFF {
-                    // FIXME: negative values!
-                    panic!("TODO: jmp n > 0x7FFF not implemented"); // support 24bit / const
-                }
-                out.push(Op::PushImmediate((rel_addr - 1) as i16));
-                out.push(Op::Jmp(cond.clone()));
+                let rel_addr = label_location(labels, label)? as i64 - out.len() as i64;
+                out.push(Op::PushImmediate(immediate(rel_addr - 1, "jmp offset")?)); // TODO: support 24bit / const
+                out.push(Op::Jmp(*cond));
             }
             Stmt::Jmp(cond, None) => {
-                if out.len() > 0x7FFF {
-                    panic!("TODO: jmp n > 0x7FFF not implemented"); // support 24bit / const
-                }
-                out.push(Op::PushImmediate(out.len() as i16 + 2));
+                out.push(Op::PushImmediate(immediate(
+                    out.len() as i64 + 2,
+                    "jmp offset",
+                )?)); // TODO: support 24bit / const
                 out.push(Op::Arith(ArithOp::Sub));
-                out.push(Op::Jmp(cond.clone()));
+                out.push(Op::Jmp(*cond));
             }
-            Stmt::Arith(op) => out.push(Op::Arith(op.clone())),
+            Stmt::Arith(op) => out.push(Op::Arith(*op)),
+            Stmt::Concat => out.push(Op::Concat),
+            Stmt::Len => out.push(Op::Len),
+            Stmt::NewArray(n) => {
+                out.push(Op::PushImmediate(immediate(*n, "array size")?));
+                out.push(Op::NewArray);
+            }
+            Stmt::LoadIndex => out.push(Op::LoadIndex),
+            Stmt::StoreIndex => out.push(Op::StoreIndex),
+            Stmt::NewRecord(n) => {
+                out.push(Op::PushImmediate(immediate(*n, "record size")?));
+                out.push(Op::NewRecord);
+            }
+            Stmt::LoadField(offset) => out.push(Op::LoadField(immediate(*offset, "field offset")?)),
+            Stmt::StoreField(offset) => {
+                out.push(Op::StoreField(immediate(*offset, "field offset")?))
+            }
+            Stmt::Unary(op) => out.push(Op::Unary(*op)),
             Stmt::Output(channel) => out.push(Op::Output(*channel as u16)),
             Stmt::Pop(n) if *n == 0 => (), // the compiler will just stupidly emit 'pop 0' in some cases
             Stmt::Pop(n) if *n == 1 => out.push(Op::Pop(PopMode::One)),
             Stmt::Pop(n) => {
-                if *n > 0x7FFF {
-                    panic!("TODO: pop n > 0x7FFF not implemented"); // support 24bit / const push
-                }
-                out.push(Op::PushImmediate(*n as i16));
+                out.push(Op::PushImmediate(immediate(*n, "pop count")?)); // TODO: support 24bit / const push
                 out.push(Op::Pop(PopMode::Top));
             }
             Stmt::Move(offs) => {
-                if *offs > 0x7FFF {
-                    panic!("TODO: pop n > 0x7FFF not implemented"); // support 24bit / const push
-                }
-                out.push(Op::PushImmediate(*offs as i16));
+                out.push(Op::PushImmediate(immediate(*offs, "move offset")?)); // TODO: support 24bit / const push
                 out.push(Op::Move);
             }
             Stmt::Label(_) => (),
             Stmt::Noop => out.push(Op::Noop),
         }
+        Ok(())
+    }
+}
+
+// values that fit into PushImmediate or PushImmediate24, everything else goes to the constant section
+fn is_inline(v: i64) -> bool {
+    v >= i16::MIN as i64 && v <= 0xFFFFFF
+}
+
+fn immediate(v: i64, what: &str) -> Result<i16> {
+    if v < i16::MIN as i64 || v > i16::MAX as i64 {
+        return Err(Error::Assemble(format!("{} out of range: {}", what, v)));
     }
+    Ok(v as i16)
+}
+
+fn label_location(labels: &HashMap<String, usize>, label: &str) -> Result<usize> {
+    labels
+        .get(label)
+        .copied()
+        .ok_or_else(|| Error::Assemble(format!("unknown label: {}", label)))
 }
 
 pub fn label_locations(stmts: &Vec<Stmt>) -> HashMap<String, usize> {
@@ -201,17 +309,173 @@ pub fn label_locations(stmts: &Vec<Stmt>) -> HashMap<String, usize> {
     labels
 }
 
-pub fn extract_constants(stmts: &Vec<Stmt>, c: &mut Vec<i64>) {
+pub fn extract_constants(stmts: &Vec<Stmt>, c: &mut Vec<Constant>) {
     for stmt in stmts {
-        match stmt {
-            Stmt::PushInline(v) if *v > 0xFFFFFF => {
-                if !c.contains(v) {
-                    c.push(v.clone());
+        let constant = match stmt {
+            Stmt::PushInline(v) if !is_inline(*v) => Constant::Int(*v),
+            Stmt::PushString(s) => Constant::Str(s.clone()),
+            _ => continue,
+        };
+        if !c.contains(&constant) {
+            c.push(constant);
+        }
+    }
+}
+
+/// Assemble a data and a code section (as produced by `xas::ProgramParser` or the compiler) into a program.
+pub fn assemble(sections: &[Section]) -> Result<Program> {
+    let mut program = Program::new();
+    for section in sections {
+        match section {
+            Section::Data(d) => program.data.extend_from_slice(d),
+            Section::Code(stmts) => {
+                let labels = label_locations(stmts);
+                let base = program.code.len();
+                let mut symbols: Vec<_> = labels
+                    .iter()
+                    .map(|(l, ip)| (l.clone(), base + ip))
+                    .collect();
+                symbols.sort_by(|(a, ip_a), (b, ip_b)| (ip_a, a).cmp(&(ip_b, b)));
+                program.symbols.extend(symbols);
+                extract_constants(stmts, &mut program.data);
+                for stmt in stmts {
+                    stmt.emit(&labels, &program.data, &mut program.code)?;
                 }
             }
-            _ => (),
         }
     }
+    program.code.push(Op::Noop);
+    Ok(program)
+}
+
+/// Disassemble a program into a data and a code section which `assemble` turns back into the same
+/// bytecode. The targets of jumps, calls and closures get labels, named after the program's symbols
+/// where it has them and `L<address>` otherwise.
+pub fn disassemble(program: &Program) -> Result<Vec<Section>> {
+    let code = match program.code.split_last() {
+        // dropped, `assemble` appends it again
+        Some((Op::Noop, code)) => code,
+        _ => &program.code[..],
+    };
+    let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for (name, address) in &program.symbols {
+        labels.entry(*address).or_default().push(name.clone());
+    }
+    let mut stmts = Vec::new();
+    let mut ip = 0;
+    while ip < code.len() {
+        let (stmt, len) = decode(code, ip, &mut labels)?;
+        stmts.push((ip, stmt));
+        ip += len;
+    }
+
+    let mut out = Vec::new();
+    let mut labels = labels.into_iter().peekable();
+    for (ip, stmt) in stmts {
+        while let Some((address, names)) = labels.next_if(|(address, _)| *address <= ip) {
+            if address < ip {
+                return Err(Error::Assemble(format!(
+                    "label `{}` at {} is inside an instruction",
+                    names[0], address
+                )));
+            }
+            out.extend(names.into_iter().map(Stmt::Label));
+        }
+        out.push(stmt);
+    }
+    for (address, names) in labels {
+        if address > code.len() {
+            return Err(Error::Assemble(format!(
+                "label `{}` out of range: {}",
+                names[0], address
+            )));
+        }
+        out.extend(names.into_iter().map(Stmt::Label));
+    }
+    Ok(vec![
+        Section::Data(program.data.clone()),
+        Section::Code(out),
+    ])
+}
+
+// Decodes the statement starting at `ip`, recognizing the op sequences `Stmt::emit` produces.
+// Returns the statement and the number of ops it takes.
+fn decode(
+    code: &[Op],
+    ip: usize,
+    labels: &mut BTreeMap<usize, Vec<String>>,
+) -> Result<(Stmt, usize)> {
+    if let [Op::PushImmediate(v), Op::Arith(ArithOp::Sub), Op::Jmp(cond), ..] = code[ip..] {
+        if v as i64 == ip as i64 + 2 {
+            return Ok((Stmt::Jmp(cond, None), 3));
+        }
+    }
+    if let [Op::PushImmediate(v), op, ..] = code[ip..] {
+        let v = v as i64;
+        let stmt = match op {
+            Op::PushConst => Some(Stmt::PushConst(v)),
+            Op::PushStack => Some(Stmt::PushStack(v)),
+            Op::Call => Some(Stmt::Call(target_label(labels, ip, v)?)),
+            Op::MakeClosure(n) => Some(Stmt::Closure(target_label(labels, ip, v)?, n as i64)),
+            Op::Jmp(cond) => Some(Stmt::Jmp(cond, Some(target_label(labels, ip, v)?))),
+            Op::Pop(PopMode::Top) => Some(Stmt::Pop(v)),
+            Op::Move => Some(Stmt::Move(v)),
+            Op::NewArray => Some(Stmt::NewArray(v)),
+            Op::NewRecord => Some(Stmt::NewRecord(v)),
+            _ => None,
+        };
+        if let Some(stmt) = stmt {
+            return Ok((stmt, 2));
+        }
+    }
+    let stmt = match code[ip] {
+        Op::Noop => Stmt::Noop,
+        Op::PushImmediate(v) => Stmt::PushInline(v as i64),
+        Op::PushImmediate24(v) => Stmt::PushInline(Into::<u32>::into(v) as i64),
+        Op::PushBool(v) => Stmt::PushBool(v),
+        Op::Arith(op) => Stmt::Arith(op),
+        Op::Unary(op) => Stmt::Unary(op),
+        Op::Output(channel) => Stmt::Output(channel as i64),
+        Op::Pop(PopMode::One) => Stmt::Pop(1),
+        Op::Ret => Stmt::Ret,
+        Op::LoadLocal(slot) => Stmt::LoadLocal(slot as i64),
+        Op::StoreLocal(slot) => Stmt::StoreLocal(slot as i64),
+        Op::Concat => Stmt::Concat,
+        Op::Len => Stmt::Len,
+        Op::LoadIndex => Stmt::LoadIndex,
+        Op::StoreIndex => Stmt::StoreIndex,
+        Op::LoadField(offset) => Stmt::LoadField(offset as i64),
+        Op::StoreField(offset) => Stmt::StoreField(offset as i64),
+        Op::CallIndirect => Stmt::CallIndirect,
+        Op::LoadGlobal(slot) => Stmt::LoadGlobal(slot as i64),
+        Op::StoreGlobal(slot) => Stmt::StoreGlobal(slot as i64),
+        op => {
+            return Err(Error::Assemble(format!(
+                "cannot disassemble {:?} at {}",
+                op, ip
+            )))
+        }
+    };
+    Ok((stmt, 1))
+}
+
+// The label of a relative jump target, pushed as `target - ip - 1` by the op at `ip`.
+fn target_label(
+    labels: &mut BTreeMap<usize, Vec<String>>,
+    ip: usize,
+    offset: i64,
+) -> Result<String> {
+    let target = ip as i64 + 1 + offset;
+    if target < 0 {
+        return Err(Error::Assemble(format!(
+            "jmp target out of range: {}",
+            target
+        )));
+    }
+    let names = labels
+        .entry(target as usize)
+        .or_insert_with(|| vec![format!("L{}", target)]);
+    Ok(names[0].clone())
 }
 
 lalrpop_mod!(pub xas);
@@ -233,7 +497,7 @@ fn asm_basic() {
         let mut bc = Vec::new();
 
         for stmt in stmts {
-            stmt.emit(&labels, &data, &mut bc);
+            stmt.emit(&labels, &data, &mut bc).unwrap();
         }
         bc.push(Op::Noop);
         println!("bc: {:?}", bc);
@@ -307,7 +571,7 @@ fn asm_labels() {
     let mut bc = Vec::new();
 
     for stmt in stmts {
-        stmt.emit(&labels, &data, &mut bc);
+        stmt.emit(&labels, &data, &mut bc).unwrap();
     }
     bc.push(Op::Noop);
     assert_eq!(bc[*labels.get("jmp_call").unwrap()], Op::PushImmediate(133));
@@ -349,3 +613,228 @@ fn asm_labels() {
     );
     // debug!("bc: {:?}", bc);
 }
+
+#[test]
+fn asm_errors() {
+    let stmts = vec![Stmt::Jmp(Cond::Always, Some("nowhere".into()))];
+    assert_eq!(
+        assemble(&[Section::Code(stmts)]).err(),
+        Some(Error::Assemble("unknown label: nowhere".into()))
+    );
+    let stmts = vec![Stmt::Pop(0x8000)];
+    assert_eq!(
+        assemble(&[Section::Code(stmts)]).err(),
+        Some(Error::Assemble("pop count out of range: 32768".into()))
+    );
+}
+
+#[test]
+fn asm_locals() {
+    let program = xas::ProgramParser::new()
+        .parse("section .code\n    load -2\n    store 1\n    ret\n")
+        .unwrap();
+    if let Section::Code(stmts) = &program[0] {
+        assert_eq!(
+            stmts[..],
+            [Stmt::LoadLocal(-2), Stmt::StoreLocal(1), Stmt::Ret]
+        );
+    } else {
+        panic!("expected code section");
+    }
+    assert_eq!(
+        assemble(&program).unwrap().code,
+        [Op::LoadLocal(-2), Op::StoreLocal(1), Op::Ret, Op::Noop]
+    );
+}
+
+#[test]
+fn asm_strings() {
+    let program = xas::ProgramParser::new()
+        .parse("section .const\n    \"a\\tb\"\nsection .code\n    push \"xy\"\n    push \"a\\tb\"\n    concat\n    len\n")
+        .unwrap();
+    if let Section::Code(stmts) = &program[1] {
+        assert_eq!(
+            stmts[..],
+            [
+                Stmt::PushString("xy".into()),
+                Stmt::PushString("a\tb".into()),
+                Stmt::Concat,
+                Stmt::Len
+            ]
+        );
+    } else {
+        panic!("expected code section");
+    }
+    let prog = assemble(&program).unwrap();
+    assert_eq!(
+        prog.data,
+        [Constant::Str("a\tb".into()), Constant::Str("xy".into())]
+    );
+    assert_eq!(
+        prog.code,
+        [
+            Op::PushImmediate(1),
+            Op::PushConst,
+            Op::PushImmediate(0),
+            Op::PushConst,
+            Op::Concat,
+            Op::Len,
+            Op::Noop
+        ]
+    );
+
+    // printed sections parse back to the same strings, whatever they contain
+    let strings = ["a\u{1b}[0m", "'\"\\\n"];
+    let program = vec![
+        Section::Data(vec![Constant::Str(strings[0].into())]),
+        Section::Code(vec![Stmt::PushString(strings[1].into())]),
+    ];
+    let mut out = Vec::new();
+    for section in &program {
+        section.print_lines(&mut out);
+    }
+    let text = String::from_utf8(out).unwrap();
+    assert_eq!(
+        text,
+        "section .const\n\"a\\u{1b}[0m\"\nsection .code\n    push \"'\\\"\\\\\\n\"\n"
+    );
+    let parsed = xas::ProgramParser::new().parse(&text).unwrap();
+    assert_eq!(
+        assemble(&parsed).unwrap().data,
+        assemble(&program).unwrap().data
+    );
+}
+
+#[test]
+fn asm_arrays() {
+    let program = xas::ProgramParser::new()
+        .parse("section .code\n    newarray 2\n    loadidx\n    storeidx\n")
+        .unwrap();
+    assert_eq!(
+        assemble(&program).unwrap().code,
+        [
+            Op::PushImmediate(2),
+            Op::NewArray,
+            Op::LoadIndex,
+            Op::StoreIndex,
+            Op::Noop
+        ]
+    );
+}
+
+#[test]
+fn asm_closures() {
+    let program = xas::ProgramParser::new()
+        .parse("section .code\nf:\n    closure f 2\n    calls\n")
+        .unwrap();
+    assert_eq!(
+        assemble(&program).unwrap().code,
+        [
+            Op::PushImmediate(-1),
+            Op::MakeClosure(2),
+            Op::CallIndirect,
+            Op::Noop
+        ]
+    );
+}
+
+#[test]
+fn asm_records() {
+    let program = xas::ProgramParser::new()
+        .parse("section .code\n    newrecord 3\n    loadfield 2\n    storefield 0\n")
+        .unwrap();
+    assert_eq!(
+        assemble(&program).unwrap().code,
+        [
+            Op::PushImmediate(3),
+            Op::NewRecord,
+            Op::LoadField(2),
+            Op::StoreField(0),
+            Op::Noop
+        ]
+    );
+}
+
+#[test]
+fn disasm() {
+    let program = xas::ProgramParser::new()
+        .parse(
+            "section .code\n    push 100000000\n    call f\n    jmps z\nf:\n    pop 2\n    ret\n",
+        )
+        .unwrap();
+    let program = assemble(&program).unwrap();
+    let mut out = Vec::new();
+    for section in disassemble(&program).unwrap() {
+        section.print_lines(&mut out);
+    }
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "section .const\n100000000\nsection .code\n    push const.0\n    call f\n    jmps z\nf:\n    pop 2\n    ret\n"
+    );
+
+    // synthesized labels, the trailing `noop` is the one `assemble` appends
+    let program = Program {
+        code: vec![
+            Op::PushImmediate(1),
+            Op::Jmp(Cond::Always),
+            Op::Noop,
+            Op::Noop,
+        ],
+        ..Program::new()
+    };
+    let sections = disassemble(&program).unwrap();
+    assert!(
+        matches!(&sections[1], Section::Code(stmts) if stmts[..] == [
+            Stmt::Jmp(Cond::Always, Some("L2".into())),
+            Stmt::Label("L2".into()),
+            Stmt::Noop,
+        ])
+    );
+    assert_eq!(assemble(&sections).unwrap().code, program.code);
+
+    let program = Program {
+        code: vec![Op::Call],
+        ..Program::new()
+    };
+    assert_eq!(
+        disassemble(&program).unwrap_err().to_string(),
+        "assembler error: cannot disassemble Call at 0"
+    );
+}
+
+// every program the compiler builds comes back unchanged from the assembler after a round trip
+// through xas text
+#[test]
+fn disasm_roundtrip() {
+    let mut paths: Vec<_> = std::fs::read_dir("data")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("l1"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+    let mut programs: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            let code = std::fs::read_to_string(path).unwrap();
+            (path.display().to_string(), code)
+        })
+        .collect();
+    // strings with control characters, quotes and backslashes have to be escaped in the listing
+    programs.push((
+        "strings".into(),
+        "print \"a\u{1b}[0m\\u{7}\", \"\\\"q\\\" \\\\ \\t\";".into(),
+    ));
+    for (name, code) in programs {
+        let program = crate::compile::Compiler::new().build(&code).unwrap();
+        let mut out = Vec::new();
+        for section in disassemble(&program).unwrap() {
+            section.print_lines(&mut out);
+        }
+        let text = String::from_utf8(out).unwrap();
+        let sections = xas::ProgramParser::new().parse(&text).unwrap();
+        let reassembled = assemble(&sections).unwrap();
+        assert_eq!(reassembled.code, program.code, "{}", name);
+        assert_eq!(reassembled.data, program.data, "{}", name);
+    }
+}
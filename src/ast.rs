@@ -9,17 +9,185 @@ pub enum Toplevel {
 
 #[derive(Debug, Clone)]
 pub enum Declaration {
-    Function(Ident, Vec<Ident>, Stmt),
+    Function(SpannedIdent, Vec<SpannedIdent>, Box<Stmt>),
+    /// `struct Name { fields }`. The field types are inferred from their uses.
+    Struct(SpannedIdent, Vec<SpannedIdent>),
+    /// `import "path";`, resolved relative to the importing file by [`crate::module::Modules`].
+    Import(Spanned<String>),
 }
 
+impl Toplevel {
+    /// Move all spans `offset` bytes further into the source, for code that was parsed on its own
+    /// but is reported as part of a larger source.
+    pub fn offset(&mut self, offset: usize) {
+        match self {
+            Toplevel::Stmt(stmt) => stmt.offset(offset),
+            Toplevel::Declaration(Declaration::Function(name, args, body)) => {
+                name.offset_span(offset);
+                args.iter_mut().for_each(|arg| arg.offset_span(offset));
+                body.offset(offset);
+            }
+            Toplevel::Declaration(Declaration::Struct(name, fields)) => {
+                name.offset_span(offset);
+                fields
+                    .iter_mut()
+                    .for_each(|field| field.offset_span(offset));
+            }
+            Toplevel::Declaration(Declaration::Import(file)) => file.offset_span(offset),
+        }
+    }
+}
+
+/// Byte offsets into the source code, as produced by LALRPOP's `@L` / `@R`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start, other.end)
+    }
+    /// The span moved `offset` bytes further into the source.
+    pub fn offset(self, offset: usize) -> Span {
+        Span::new(self.start + offset, self.end + offset)
+    }
+}
+
+/// A syntax tree node together with its location in the source code.
+#[derive(Clone, Copy)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, start: usize, end: usize) -> Self {
+        Spanned {
+            node,
+            span: Span::new(start, end),
+        }
+    }
+}
+
+impl<T> Spanned<T> {
+    fn offset_span(&mut self, offset: usize) {
+        self.span = self.span.offset(offset);
+    }
+}
+
+impl Stmt {
+    fn offset(&mut self, offset: usize) {
+        self.span = self.span.offset(offset);
+        match &mut self.node {
+            StmtKind::LetBinding(ident, expr) | StmtKind::Assign(ident, expr, _) => {
+                ident.offset_span(offset);
+                expr.offset(offset);
+            }
+            StmtKind::AssignIndex(array, index, expr, _) => {
+                array.offset(offset);
+                index.offset(offset);
+                expr.offset(offset);
+            }
+            StmtKind::AssignField(record, field, expr, _) => {
+                record.offset(offset);
+                field.offset_span(offset);
+                expr.offset(offset);
+            }
+            StmtKind::Print(exprs) => exprs.iter_mut().for_each(|e| e.offset(offset)),
+            StmtKind::IfElse(cond, if_body, else_body) => {
+                cond.offset(offset);
+                if_body.offset(offset);
+                if let Some(else_body) = else_body {
+                    else_body.offset(offset);
+                }
+            }
+            StmtKind::While(cond, body) => {
+                cond.offset(offset);
+                body.offset(offset);
+            }
+            StmtKind::For(ident, start, end, body) => {
+                ident.offset_span(offset);
+                start.offset(offset);
+                end.offset(offset);
+                body.offset(offset);
+            }
+            StmtKind::Break | StmtKind::Continue => (),
+            StmtKind::Block(stmts) => stmts.iter_mut().for_each(|s| s.offset(offset)),
+            StmtKind::Call(expr) | StmtKind::Return(expr) => expr.offset(offset),
+        }
+    }
+}
+
+impl Expr {
+    fn offset(&mut self, offset: usize) {
+        self.span = self.span.offset(offset);
+        match &mut self.node {
+            ExprKind::Number(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Str(_)
+            | ExprKind::EnvLoad(_)
+            | ExprKind::Error => (),
+            ExprKind::Array(elements) => elements.iter_mut().for_each(|e| e.offset(offset)),
+            ExprKind::Index(l, r) | ExprKind::Op(l, _, r) => {
+                l.offset(offset);
+                r.offset(offset);
+            }
+            ExprKind::Struct(name, fields) => {
+                name.offset_span(offset);
+                for (field, value) in fields {
+                    field.offset_span(offset);
+                    value.offset(offset);
+                }
+            }
+            ExprKind::Field(record, field) => {
+                record.offset(offset);
+                field.offset_span(offset);
+            }
+            ExprKind::Unary(_, e) => e.offset(offset),
+            ExprKind::Call(name, args) => {
+                name.offset_span(offset);
+                args.iter_mut().for_each(|arg| arg.offset(offset));
+            }
+            ExprKind::Function(args, body) => {
+                args.iter_mut().for_each(|arg| arg.offset_span(offset));
+                body.offset(offset);
+            }
+        }
+    }
+}
+
+// print only the node, so that spans do not clutter the debug output of whole trees
+impl<T: Debug> Debug for Spanned<T> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        self.node.fmt(fmt)
+    }
+}
+
+pub type Stmt = Spanned<StmtKind>;
+pub type Expr = Spanned<ExprKind>;
+pub type SpannedIdent = Spanned<Ident>;
+
 #[derive(Debug, Clone)]
-pub enum Stmt {
-    LetBinding(Ident, Expr),
-    Assign(Ident, Expr, Option<Opcode>),
+pub enum StmtKind {
+    LetBinding(SpannedIdent, Expr),
+    Assign(SpannedIdent, Expr, Option<Opcode>),
+    /// `array[index] = value`, or a compound assignment to an array element.
+    AssignIndex(Expr, Expr, Expr, Option<Opcode>),
+    /// `record.field = value`, or a compound assignment to a field.
+    AssignField(Expr, SpannedIdent, Expr, Option<Opcode>),
     Print(Vec<Expr>),
     IfElse(Expr, Box<Stmt>, Option<Box<Stmt>>),
     While(Expr, Box<Stmt>),
-    Block(Vec<Stmt>, bool),
+    /// `for i in start..end body`, counting up to (excluding) `end`, which is evaluated once.
+    For(SpannedIdent, Expr, Expr, Box<Stmt>),
+    Break,
+    Continue,
+    Block(Vec<Stmt>),
     Call(Expr),
     Return(Expr),
 }
@@ -42,20 +210,67 @@ pub type Ident = Handle;
 
 // #[derive(Debug)]
 #[derive(Clone)]
-pub enum Expr {
+pub enum ExprKind {
     Number(i64),
     EnvLoad(Ident),
+    Bool(bool),
+    Str(String),
+    Array(Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    /// `Name { field: value, .. }`. The values are evaluated in the order of the declaration.
+    Struct(SpannedIdent, Vec<(SpannedIdent, Expr)>),
+    Field(Box<Expr>, SpannedIdent),
     Op(Box<Expr>, Opcode, Box<Expr>),
-    Call(Ident, Vec<Expr>),
+    Unary(UnOp, Box<Expr>),
+    /// Call of a function declared with `fn name(..)`, or of a function value stored in a local
+    /// binding of that name.
+    Call(SpannedIdent, Vec<Expr>),
+    /// Anonymous function `fn(args) { body }`. Bindings of the enclosing scopes used in the body
+    /// are captured by value when the function value is created.
+    Function(Vec<SpannedIdent>, Box<Stmt>),
     Error,
 }
 
+/// The types of lang1 values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Bool,
+    Str,
+    Array(Box<Type>),
+    Struct(String),
+    Function(Vec<Type>, Box<Type>),
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Type::Int => write!(fmt, "int"),
+            Type::Bool => write!(fmt, "bool"),
+            Type::Str => write!(fmt, "string"),
+            Type::Array(element) => write!(fmt, "[{}]", element),
+            Type::Struct(name) => write!(fmt, "{}", name),
+            Type::Function(args, ret) => {
+                let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(fmt, "fn({}) -> {}", args.join(", "), ret)
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum Opcode {
     Mul,
     Div,
+    Mod,
     Add,
     Sub,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Ushr,
     Or,
     And,
     Equal,
@@ -66,14 +281,30 @@ pub enum Opcode {
     GreaterEqual,
 }
 
-impl Debug for Expr {
+#[derive(Copy, Clone)]
+pub enum UnOp {
+    Neg,
+    Not,
+    Complement,
+    Len,
+}
+
+impl Debug for ExprKind {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        use self::Expr::*;
+        use self::ExprKind::*;
         match *self {
             Number(n) => write!(fmt, "{:?}", n),
+            Bool(b) => write!(fmt, "{:?}", b),
+            Str(ref s) => write!(fmt, "{:?}", s),
+            Array(ref elements) => write!(fmt, "{:?}", elements),
+            Index(ref a, ref i) => write!(fmt, "{:?}[{:?}]", a, i),
+            Struct(ref name, ref fields) => write!(fmt, "{:?} {:?}", name, fields),
+            Field(ref e, ref field) => write!(fmt, "{:?}.{:?}", e, field),
             Op(ref l, op, ref r) => write!(fmt, "({:?} {:?} {:?})", l, op, r),
+            Unary(op, ref e) => write!(fmt, "({:?}{:?})", op, e),
             EnvLoad(ident) => write!(fmt, "load({:?})", ident),
-            Call(name, _) => write!(fmt, "call {:?}(...)", name),
+            Call(ref name, _) => write!(fmt, "call {:?}(...)", name),
+            Function(ref args, _) => write!(fmt, "fn({:?}) {{...}}", args),
             Error => write!(fmt, "error"),
         }
     }
@@ -85,8 +316,15 @@ impl Debug for Opcode {
         match *self {
             Mul => write!(fmt, "*"),
             Div => write!(fmt, "/"),
+            Mod => write!(fmt, "%"),
             Add => write!(fmt, "+"),
             Sub => write!(fmt, "-"),
+            BitAnd => write!(fmt, "&"),
+            BitOr => write!(fmt, "|"),
+            BitXor => write!(fmt, "^"),
+            Shl => write!(fmt, "<<"),
+            Shr => write!(fmt, ">>"),
+            Ushr => write!(fmt, ">>>"),
             Or => write!(fmt, "or"),
             And => write!(fmt, "and"),
             Equal => write!(fmt, "=="),
@@ -99,6 +337,18 @@ impl Debug for Opcode {
     }
 }
 
+impl Debug for UnOp {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        use self::UnOp::*;
+        match *self {
+            Neg => write!(fmt, "-"),
+            Not => write!(fmt, "not "),
+            Complement => write!(fmt, "~"),
+            Len => write!(fmt, "len "),
+        }
+    }
+}
+
 #[test]
 fn test_handy() {
     let mut map = HandleMap::new();
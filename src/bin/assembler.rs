@@ -1,32 +1,33 @@
 use lalrpop_test::{
-    asm::{extract_constants, label_locations, xas, BytecodeEmit, Section},
-    bytecode::{Op, Program},
+    asm::{assemble, xas},
+    error::Result,
 };
-use std::io::Read;
+use std::io::{Read, Write};
 
 fn main() {
     env_logger::init();
 
     let mut code = String::new();
     std::io::stdin().lock().read_to_string(&mut code).unwrap();
-    let program = xas::ProgramParser::new().parse(&code[..]).unwrap();
 
-    if let (Section::Data(data), Section::Code(stmts)) = (&program[0], &program[1]) {
-        let mut data = data.clone();
-        let labels = label_locations(stmts);
-        extract_constants(stmts, &mut data);
-        let mut code = Vec::new();
-        for stmt in stmts {
-            stmt.emit(&labels, &data, &mut code);
+    let prog = match assemble_source(&code[..]) {
+        Ok(prog) => prog,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
         }
-        code.push(Op::Noop);
-
-        // let mut vm = Vm::new();
-        // vm.data = data.clone();
-        // vm.code = bc;
-        // println!("data: {:?}", vm.data);
-        // println!("code: {:?}", vm.code);
-        let prog = Program { data, code };
+    };
+    // `.l1b` by default, YAML for reading the program
+    if std::env::args().any(|arg| arg == "--yaml") {
         serde_yaml::to_writer(&mut std::io::stdout().lock(), &prog).unwrap();
+    } else {
+        std::io::stdout()
+            .lock()
+            .write_all(&prog.to_bytes())
+            .unwrap();
     }
 }
+
+fn assemble_source(code: &str) -> Result<lalrpop_test::bytecode::Program> {
+    assemble(&xas::ProgramParser::new().parse(code)?)
+}
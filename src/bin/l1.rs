@@ -0,0 +1,289 @@
+use handy::HandleMap;
+use lalrpop_test::{
+    asm::{self, xas, Disass, Section},
+    ast::Toplevel,
+    bytecode::{IoChannels, Program, Vm},
+    compile::Compiler,
+    error::Error,
+    module::Modules,
+    typeck,
+};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+
+const USAGE: &str = "\
+usage: l1 <command> [options] <file>
+
+commands:
+  run      run a program
+  build    compile a lang1 program (emits `bin` by default)
+  asm      assemble an xas file (emits `yaml` by default)
+  disasm   turn a program back into assembly (emits `asm` by default)
+  check    parse and type check a lang1 program
+
+options:
+  --emit=<stage>  output ast, asm, bytecode, yaml or bin (.l1b) instead
+  -o <file>       write the output to a file instead of stdout
+
+Inputs are read according to their extension: lang1 source (.l1), assembly (.xas), or a program
+as YAML (.yaml, .yml) or in the binary format (.l1b).
+";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Run,
+    Build,
+    Asm,
+    Disasm,
+    Check,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    Ast,
+    Asm,
+    Bytecode,
+    Yaml,
+    Bin,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Source,
+    Assembly,
+    Yaml,
+    Bin,
+}
+
+struct Options {
+    command: Command,
+    emit: Option<Emit>,
+    output: Option<PathBuf>,
+    input: PathBuf,
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprint!("error: {}\n\n{}", msg, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(msg) = execute(&options) {
+        eprint!("{}", msg);
+        std::process::exit(1);
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(String::as_str) {
+        Some("run") => Command::Run,
+        Some("build") => Command::Build,
+        Some("asm") => Command::Asm,
+        Some("disasm") => Command::Disasm,
+        Some("check") => Command::Check,
+        Some(command) => return Err(format!("unknown command `{}`", command)),
+        None => return Err("missing command".into()),
+    };
+    let mut emit = None;
+    let mut output = None;
+    let mut input = None;
+    while let Some(arg) = args.next() {
+        if let Some(stage) = arg.strip_prefix("--emit=") {
+            emit = Some(match stage {
+                "ast" => Emit::Ast,
+                "asm" => Emit::Asm,
+                "bytecode" => Emit::Bytecode,
+                "yaml" => Emit::Yaml,
+                "bin" => Emit::Bin,
+                _ => return Err(format!("unknown stage `{}`", stage)),
+            });
+        } else if arg == "-o" {
+            let path = args.next().ok_or("missing file after `-o`")?;
+            output = Some(PathBuf::from(path));
+        } else if arg.starts_with('-') {
+            return Err(format!("unknown option `{}`", arg));
+        } else if input.replace(PathBuf::from(arg)).is_some() {
+            return Err(format!("unexpected argument `{}`", arg));
+        }
+    }
+    if emit.is_some() && matches!(command, Command::Run | Command::Check) {
+        return Err("`--emit` is only supported by build, asm and disasm".into());
+    }
+    let input = input.ok_or("missing input file")?;
+    Ok(Options {
+        command,
+        emit,
+        output,
+        input,
+    })
+}
+
+fn format(path: &Path) -> Result<Format, String> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("l1") => Ok(Format::Source),
+        Some("xas") => Ok(Format::Assembly),
+        Some("yaml") | Some("yml") => Ok(Format::Yaml),
+        Some("l1b") => Ok(Format::Bin),
+        _ => Err(format!(
+            "cannot tell the format of `{}` from its extension\n",
+            path.display()
+        )),
+    }
+}
+
+fn execute(options: &Options) -> Result<(), String> {
+    let input = &options.input;
+    let format = format(input)?;
+    let expect = |expected: Format, what: &str| {
+        if format == expected {
+            Ok(())
+        } else {
+            Err(format!("`{}` is not {}\n", input.display(), what))
+        }
+    };
+    let emit = match options.command {
+        Command::Run => return run(program(input, format)?),
+        Command::Check => {
+            expect(Format::Source, "a lang1 program")?;
+            return frontend(input, |_, _| Ok(()));
+        }
+        Command::Build => {
+            expect(Format::Source, "a lang1 program")?;
+            options.emit.unwrap_or(Emit::Bin)
+        }
+        Command::Asm => {
+            expect(Format::Assembly, "an xas file")?;
+            options.emit.unwrap_or(Emit::Yaml)
+        }
+        Command::Disasm => options.emit.unwrap_or(Emit::Asm),
+    };
+    let output = match emit {
+        Emit::Ast => {
+            expect(Format::Source, "a lang1 program")?;
+            frontend(input, |_, program| {
+                Ok(program
+                    .iter()
+                    .map(|t| format!("{:?}\n", t))
+                    .collect::<String>())
+            })?
+            .into_bytes()
+        }
+        Emit::Asm => {
+            let mut out = Vec::new();
+            for section in sections(input, format)? {
+                section.print_lines(&mut out);
+            }
+            out
+        }
+        Emit::Bytecode => program(input, format)?.to_string().into_bytes(),
+        Emit::Yaml => serde_yaml::to_string(&program(input, format)?)
+            .map_err(|err| format!("{}\n", err))?
+            .into_bytes(),
+        Emit::Bin => program(input, format)?.to_bytes(),
+    };
+    match &options.output {
+        Some(path) => std::fs::write(path, output)
+            .map_err(|err| format!("cannot write `{}`: {}\n", path.display(), err)),
+        None => std::io::stdout()
+            .write_all(&output)
+            .map_err(|err| format!("{}\n", err)),
+    }
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| format!("cannot read `{}`: {}\n", path.display(), err))
+}
+
+// Loads, links and type checks a lang1 program with its imports, and passes it on to `f`. Errors
+// are rendered against the files they occurred in.
+fn frontend<T>(
+    path: &Path,
+    f: impl FnOnce(&HandleMap<&str>, &[Toplevel]) -> Result<T, Vec<Error>>,
+) -> Result<T, String> {
+    let mut modules = Modules::new();
+    let mut env = HandleMap::new();
+    modules
+        .load(path)
+        .and_then(|_| modules.parse(&mut env))
+        .and_then(|program| typeck::check(&env, &program).map(|_| program))
+        .and_then(|program| f(&env, &program))
+        .map_err(|errors| errors.iter().map(|err| modules.render(err)).collect())
+}
+
+fn sections(path: &Path, format: Format) -> Result<Vec<Section>, String> {
+    match format {
+        Format::Source => frontend(path, |env, program| {
+            Compiler::new()
+                .compile(env, program)
+                .map_err(|err| vec![err])
+        }),
+        Format::Assembly => xas::ProgramParser::new()
+            .parse(&read(path)?)
+            .map_err(|err| format!("{}: {}\n", path.display(), Error::from(err))),
+        Format::Yaml | Format::Bin => asm::disassemble(&program(path, format)?)
+            .map_err(|err| format!("{}: {}\n", path.display(), err)),
+    }
+}
+
+fn program(path: &Path, format: Format) -> Result<Program, String> {
+    match format {
+        Format::Source | Format::Assembly => {
+            let mut program = asm::assemble(&sections(path, format)?)
+                .map_err(|err| format!("{}: {}\n", path.display(), err))?;
+            program.source = Some(path.display().to_string());
+            Ok(program)
+        }
+        Format::Yaml => serde_yaml::from_str(&read(path)?)
+            .map_err(|err| format!("{}: {}\n", path.display(), err)),
+        Format::Bin => {
+            let bytes = std::fs::read(path)
+                .map_err(|err| format!("cannot read `{}`: {}\n", path.display(), err))?;
+            Program::from_bytes(&bytes).map_err(|err| format!("{}: {}\n", path.display(), err))
+        }
+    }
+}
+
+// Prints the values of the program's `output` instructions, one per line, as the program runs.
+fn run(mut program: Program) -> Result<(), String> {
+    let (send, recv) = channel::<String>();
+    let printer = std::thread::spawn(move || -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        for value in recv {
+            writeln!(stdout, "{}", value)?;
+        }
+        Ok(())
+    });
+    let mut io = IoChannels::new();
+    io.channels.push(send);
+    // the VM only needs code and constants, the symbols name the functions in the backtrace
+    let symbols = Program {
+        symbols: std::mem::take(&mut program.symbols),
+        ..Program::new()
+    };
+    let mut vm = Vm::from_program(program);
+    let res = vm.exec(Some(&io));
+    // closes the channel, the printer stops after the last value
+    drop(io);
+    printer
+        .join()
+        .unwrap()
+        .map_err(|err| format!("{}\n", err))?;
+    res.map_err(|err| {
+        let mut out = format!("{}\nbacktrace:\n", err);
+        for address in vm.backtrace() {
+            match symbols.symbolize(address) {
+                Some((name, offset)) => out += &format!("  {} ({}+{})\n", address, name, offset),
+                None => out += &format!("  {}\n", address),
+            }
+        }
+        out
+    })
+}
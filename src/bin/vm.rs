@@ -1,17 +1,35 @@
-use lalrpop_test::bytecode::{IoChannels, Program, Vm};
+use lalrpop_test::{
+    bytecode::{self, IoChannels, Program, Vm},
+    error::Error,
+};
+use std::io::Read;
 use std::sync::mpsc::channel;
 
 fn main() {
     env_logger::init();
 
-    let prog: Program = serde_yaml::from_reader(&mut std::io::stdin().lock()).unwrap();
+    let mut input = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut input).unwrap();
+    // `.l1b` as written by the assembler, or YAML
+    let prog = if bytecode::is_l1b(&input) {
+        Program::from_bytes(&input)
+    } else {
+        serde_yaml::from_slice(&input).map_err(|err| Error::Format(err.to_string()))
+    };
+    let prog = match prog {
+        Ok(prog) => prog,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
     let mut vm = Vm::from_program(prog);
     // vm.max_ops = Some(1000);
 
     let (send, recv) = channel();
     let mut io_channels = IoChannels::new();
     io_channels.channels.push(send);
-    vm.exec(Some(&io_channels));
+    let res = vm.exec(Some(&io_channels));
     let mut num_out = 0;
     loop {
         if let Ok(v) = recv.try_recv() {
@@ -23,5 +41,11 @@ fn main() {
     }
     println!("num output: {}", num_out);
     println!("num ops: {}", vm.num_ops);
+    println!("gc: {:?}", vm.heap.stats);
     println!("vm: {:?}", vm);
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        eprintln!("backtrace: {:?}", vm.backtrace());
+        std::process::exit(1);
+    }
 }
@@ -1,7 +1,87 @@
+use crate::error::{Error, Result, Trap};
+use crate::heap::{Heap, Object};
+use crate::parser::escape;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 use std::sync::mpsc::Sender;
 
+/// A value on the VM stack. Operations trap if they get a value of the wrong kind.
+#[derive(Debug, Clone, Serialize, Deserialize, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    /// Reference to an [`Object::Str`](crate::heap::Object::Str) on the heap.
+    Str(usize),
+    /// Reference to an [`Object::Array`](crate::heap::Object::Array) on the heap.
+    Array(usize),
+    /// Reference to an [`Object::Record`](crate::heap::Object::Record) on the heap.
+    Record(usize),
+    /// Reference to an [`Object::Closure`](crate::heap::Object::Closure) on the heap.
+    Function(usize),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Bool(_) => "bool",
+            Value::Str(_) => "string",
+            Value::Array(_) => "array",
+            Value::Record(_) => "record",
+            Value::Function(_) => "function",
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Value::Int(v) => write!(fmt, "{}", v),
+            Value::Bool(v) => write!(fmt, "{}", v),
+            Value::Str(r) => write!(fmt, "<string @{}>", r),
+            Value::Array(r) => write!(fmt, "<array @{}>", r),
+            Value::Record(r) => write!(fmt, "<record @{}>", r),
+            Value::Function(r) => write!(fmt, "<function @{}>", r),
+        }
+    }
+}
+
+/// Entries of the constant section of a [`Program`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Constant {
+    Int(i64),
+    Str(String),
+}
+
+impl Display for Constant {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Constant::Int(v) => write!(fmt, "{}", v),
+            Constant::Str(s) => write!(fmt, "{}", escape(s)),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Int(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Bool(v)
+    }
+}
+
+fn type_mismatch(expected: &'static str, found: Value) -> Trap {
+    Trap::TypeMismatch {
+        expected,
+        found: found.type_name(),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Copy, PartialEq)]
 pub struct Uint24([u8; 3]);
 
@@ -27,35 +107,96 @@ pub enum ArithOp {
     Sub,
     Mul,
     Div,
+    Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Ushr,
     Or,
     And,
     Equal,
     NotEqual,
     LessThan,
     LessEqual,
+    GreaterThan,
+    GreaterEqual,
 }
 
-fn bool_to_i64(v: bool) -> i64 {
-    if v {
-        1
-    } else {
-        0
+impl ArithOp {
+    /// Comparisons yield booleans, `and` / `or` take booleans and everything else works on integers.
+    /// Equality is defined for two values of the same kind; arrays, records and functions are
+    /// equal if they are the same object.
+    pub fn eval(&self, a: Value, b: Value) -> std::result::Result<Value, Trap> {
+        match (*self, a, b) {
+            (ArithOp::Equal, Value::Int(_), Value::Int(_))
+            | (ArithOp::Equal, Value::Bool(_), Value::Bool(_))
+            | (ArithOp::Equal, Value::Array(_), Value::Array(_))
+            | (ArithOp::Equal, Value::Record(_), Value::Record(_))
+            | (ArithOp::Equal, Value::Function(_), Value::Function(_)) => Ok(Value::Bool(a == b)),
+            (ArithOp::NotEqual, Value::Int(_), Value::Int(_))
+            | (ArithOp::NotEqual, Value::Bool(_), Value::Bool(_))
+            | (ArithOp::NotEqual, Value::Array(_), Value::Array(_))
+            | (ArithOp::NotEqual, Value::Record(_), Value::Record(_))
+            | (ArithOp::NotEqual, Value::Function(_), Value::Function(_)) => {
+                Ok(Value::Bool(a != b))
+            }
+            (ArithOp::Equal, _, _) | (ArithOp::NotEqual, _, _) => {
+                Err(type_mismatch(a.type_name(), b))
+            }
+            (ArithOp::And, Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a && b)),
+            (ArithOp::Or, Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a || b)),
+            (ArithOp::And, Value::Bool(_), b) | (ArithOp::Or, Value::Bool(_), b) => {
+                Err(type_mismatch("bool", b))
+            }
+            (ArithOp::And, a, _) | (ArithOp::Or, a, _) => Err(type_mismatch("bool", a)),
+            (op, Value::Int(a), Value::Int(b)) => op.eval_int(a, b),
+            (_, Value::Int(_), b) => Err(type_mismatch("int", b)),
+            (_, a, _) => Err(type_mismatch("int", a)),
+        }
+    }
+
+    fn eval_int(&self, a: i64, b: i64) -> std::result::Result<Value, Trap> {
+        Ok(Value::Int(match *self {
+            ArithOp::Add => a.wrapping_add(b),
+            ArithOp::Sub => a.wrapping_sub(b),
+            ArithOp::Mul => a.wrapping_mul(b),
+            ArithOp::Div if b == 0 => return Err(Trap::DivisionByZero),
+            ArithOp::Div => a.wrapping_div(b),
+            ArithOp::Mod if b == 0 => return Err(Trap::DivisionByZero),
+            ArithOp::Mod => a.wrapping_rem(b),
+            ArithOp::BitAnd => a & b,
+            ArithOp::BitOr => a | b,
+            ArithOp::BitXor => a ^ b,
+            // shift amounts are taken modulo 64
+            ArithOp::Shl => a.wrapping_shl(b as u32),
+            ArithOp::Shr => a.wrapping_shr(b as u32),
+            ArithOp::Ushr => (a as u64).wrapping_shr(b as u32) as i64,
+            ArithOp::LessThan => return Ok(Value::Bool(a < b)),
+            ArithOp::LessEqual => return Ok(Value::Bool(a <= b)),
+            ArithOp::GreaterThan => return Ok(Value::Bool(a > b)),
+            ArithOp::GreaterEqual => return Ok(Value::Bool(a >= b)),
+            ArithOp::Or | ArithOp::And | ArithOp::Equal | ArithOp::NotEqual => unreachable!(),
+        }))
     }
 }
 
-impl ArithOp {
-    pub fn eval(&self, a: i64, b: i64) -> i64 {
-        match *self {
-            ArithOp::Add => a + b,
-            ArithOp::Sub => a - b,
-            ArithOp::Mul => a * b,
-            ArithOp::Div => a / b,
-            ArithOp::Or => bool_to_i64(a != 0 || b != 0),
-            ArithOp::And => bool_to_i64(a != 0 && b != 0),
-            ArithOp::Equal => bool_to_i64(a == b),
-            ArithOp::NotEqual => bool_to_i64(a != b),
-            ArithOp::LessThan => bool_to_i64(a < b),
-            ArithOp::LessEqual => bool_to_i64(a <= b),
+#[derive(Clone, Serialize, Deserialize, Debug, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    Complement,
+}
+
+impl UnaryOp {
+    pub fn eval(&self, a: Value) -> std::result::Result<Value, Trap> {
+        match (*self, a) {
+            (UnaryOp::Neg, Value::Int(a)) => Ok(Value::Int(a.wrapping_neg())),
+            (UnaryOp::Complement, Value::Int(a)) => Ok(Value::Int(!a)),
+            (UnaryOp::Not, Value::Bool(a)) => Ok(Value::Bool(!a)),
+            (UnaryOp::Not, a) => Err(type_mismatch("bool", a)),
+            (_, a) => Err(type_mismatch("int", a)),
         }
     }
 }
@@ -72,6 +213,7 @@ pub enum PopMode {
     Top,
 }
 
+/// Condition of a jump. `Zero` jumps if the popped value is `false`, `NonZero` if it is `true`.
 #[derive(Clone, Serialize, Deserialize, Debug, Copy, PartialEq)]
 pub enum Cond {
     Always,
@@ -87,44 +229,253 @@ pub enum Op {
     PushIp,
     PushImmediate(i16),
     PushImmediate24(Uint24),
+    PushBool(bool),
     Move,
     Arith(ArithOp),
+    Unary(UnaryOp),
     Jmp(Cond),
     Output(u16),
     Pop(PopMode),
     Break,
+    Call,
+    Ret,
+    LoadLocal(i16),
+    StoreLocal(i16),
+    Concat,
+    /// Length of a string or an array.
+    Len,
+    /// Pops the number of elements, then the elements (the first one deepest), and pushes a new
+    /// array.
+    NewArray,
+    /// Pops an index and an array, pushes the element.
+    LoadIndex,
+    /// Pops a value, an index and an array, and stores the value in the array.
+    StoreIndex,
+    /// Like `NewArray`, but creates a record. The values are the fields in order of declaration.
+    NewRecord,
+    /// Pops a record and pushes the field at the given offset.
+    LoadField(i16),
+    /// Pops a value and a record, and stores the value in the field at the given offset.
+    StoreField(i16),
+    /// Pops a relative code address and the given number of captured values, and pushes a new
+    /// function value.
+    MakeClosure(i16),
+    /// Pops a function value and calls it, like `Call`. The captured values are pushed as the
+    /// first locals of the new frame.
+    CallIndirect,
+    /// Pushes the global variable in the given slot.
+    LoadGlobal(i16),
+    /// Pops a value into the given global slot. The globals segment grows as needed.
+    StoreGlobal(i16),
 }
 
+/// A program for the [`Vm`], as produced by [`crate::asm::assemble`].
 #[derive(Serialize, Deserialize)]
 pub struct Program {
-    pub data: Vec<i64>,
+    pub data: Vec<Constant>,
     pub code: Vec<Op>,
+    /// Code labels and their addresses, e.g. `func_name` for the entry of a function.
+    #[serde(default)]
+    pub symbols: Vec<(String, usize)>,
+    /// Debug info: the file the program was built from.
+    #[serde(default)]
+    pub source: Option<String>,
 }
 impl Program {
     pub fn new() -> Self {
         Program {
             data: Vec::new(),
             code: Vec::new(),
+            symbols: Vec::new(),
+            source: None,
+        }
+    }
+
+    /// The symbol of the function (or other label) a code address belongs to, and the offset of
+    /// the address from it.
+    pub fn symbolize(&self, address: usize) -> Option<(&str, usize)> {
+        self.symbols
+            .iter()
+            .filter(|(_, start)| *start <= address)
+            .max_by_key(|(_, start)| *start)
+            .map(|(name, start)| (name.as_str(), address - start))
+    }
+
+    /// Encode the program in the `.l1b` format: a header with the [`L1B_MAGIC`] number, the
+    /// [`L1B_VERSION`] of the format, a CRC-32 checksum of the rest of the file and the number of
+    /// sections, followed by the section table and the sections. The table has an entry of kind,
+    /// offset and length for each section; code and constants are always present, symbols and
+    /// debug info if the program has them. All numbers are little-endian `u32`s, the sections are
+    /// encoded with bincode.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sections = vec![
+            (SECTION_CODE, encode(&self.code)),
+            (SECTION_CONSTANTS, encode(&self.data)),
+        ];
+        if !self.symbols.is_empty() {
+            sections.push((SECTION_SYMBOLS, encode(&self.symbols)));
+        }
+        if let Some(source) = &self.source {
+            sections.push((SECTION_DEBUG, encode(source)));
+        }
+
+        let mut body = Vec::new();
+        let mut offset = HEADER_LEN + ENTRY_LEN * sections.len();
+        for (kind, section) in &sections {
+            for word in &[*kind, offset as u32, section.len() as u32] {
+                body.extend_from_slice(&word.to_le_bytes());
+            }
+            offset += section.len();
+        }
+        for (_, section) in &sections {
+            body.extend_from_slice(section);
+        }
+
+        let mut bytes = L1B_MAGIC.to_vec();
+        for word in &[L1B_VERSION, crc32(&body), sections.len() as u32] {
+            bytes.extend_from_slice(&word.to_le_bytes());
         }
+        bytes.extend(body);
+        bytes
+    }
+
+    /// Decode a program in the `.l1b` format (see [`Program::to_bytes`]). Files of another format
+    /// version are rejected; sections of unknown kinds are skipped.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program> {
+        if !is_l1b(bytes) || bytes.len() < HEADER_LEN {
+            return Err(Error::Format("not an l1b file".into()));
+        }
+        let word = |at: usize| {
+            bytes
+                .get(at..at + 4)
+                .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+                .ok_or_else(|| Error::Format("truncated file".into()))
+        };
+        let version = word(4)?;
+        if version != L1B_VERSION {
+            return Err(Error::Format(format!(
+                "unsupported format version {} (expected {})",
+                version, L1B_VERSION
+            )));
+        }
+        if crc32(&bytes[HEADER_LEN..]) != word(8)? {
+            return Err(Error::Format("checksum mismatch".into()));
+        }
+
+        let mut program = Program::new();
+        let (mut code, mut data) = (None, None);
+        for i in 0..word(12)? as usize {
+            let entry = HEADER_LEN + i * ENTRY_LEN;
+            let (kind, offset, len) = (word(entry)?, word(entry + 4)?, word(entry + 8)?);
+            let (offset, len) = (offset as usize, len as usize);
+            let section = bytes
+                .get(offset..offset + len)
+                .ok_or_else(|| Error::Format("truncated file".into()))?;
+            match kind {
+                SECTION_CODE => code = Some(decode(section, "code")?),
+                SECTION_CONSTANTS => data = Some(decode(section, "constants")?),
+                SECTION_SYMBOLS => program.symbols = decode(section, "symbols")?,
+                SECTION_DEBUG => program.source = Some(decode(section, "debug info")?),
+                _ => (),
+            }
+        }
+        program.code = code.ok_or_else(|| Error::Format("missing code section".into()))?;
+        program.data = data.ok_or_else(|| Error::Format("missing constants section".into()))?;
+        Ok(program)
     }
 }
 
+/// Magic number at the start of `.l1b` files.
+pub const L1B_MAGIC: [u8; 4] = *b"\x7fL1B";
+/// Version of the `.l1b` format written by [`Program::to_bytes`].
+pub const L1B_VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 12;
+const SECTION_CODE: u32 = 1;
+const SECTION_CONSTANTS: u32 = 2;
+const SECTION_SYMBOLS: u32 = 3;
+const SECTION_DEBUG: u32 = 4;
+
+/// Whether the bytes start like an `.l1b` file.
+pub fn is_l1b(bytes: &[u8]) -> bool {
+    bytes.starts_with(&L1B_MAGIC)
+}
+
+fn encode<T: Serialize + ?Sized>(section: &T) -> Vec<u8> {
+    bincode::serialize(section).expect("sections can be encoded")
+}
+
+fn decode<T: serde::de::DeserializeOwned>(section: &[u8], name: &str) -> Result<T> {
+    bincode::deserialize(section)
+        .map_err(|err| Error::Format(format!("invalid {} section: {}", name, err)))
+}
+
+// CRC-32 as used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Lists the constants (`%index value`) and the instructions with their addresses.
+impl Display for Program {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        for (i, constant) in self.data.iter().enumerate() {
+            writeln!(fmt, "%{:<4} {}", i, constant)?;
+        }
+        for (i, op) in self.code.iter().enumerate() {
+            writeln!(fmt, "{:<5} {:?}", i, op)?;
+        }
+        Ok(())
+    }
+}
+
+struct Frame {
+    ret: usize,
+    fp: usize,
+}
+
 pub struct Vm {
-    pub data: Vec<i64>,
-    stack: Vec<i64>,
+    pub data: Vec<Constant>,
+    stack: Vec<Value>,
+    // slots that were never stored to are `None`
+    globals: Vec<Option<Value>>,
+    /// Strings, arrays, records and closures. They are collected when no longer reachable from the
+    /// stack or the globals.
+    pub heap: Heap,
+    call_stack: Vec<Frame>,
+    fp: usize,
     pub code: Vec<Op>,
     ip: usize,
     pub num_ops: usize,
     pub max_ops: Option<usize>,
 }
 impl std::fmt::Debug for Vm {
-    fn fmt(&self, fmt : &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        writeln!(fmt, "ip: {}, stack: {}", self.ip, self.stack.len())
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        writeln!(
+            fmt,
+            "ip: {}, fp: {}, stack: {}, call stack: {}",
+            self.ip,
+            self.fp,
+            self.stack.len(),
+            self.call_stack.len()
+        )
     }
 }
 
 pub struct IoChannels {
-    pub channels: Vec<Sender<i64>>,
+    /// Receive the output of the program, values are rendered as text.
+    pub channels: Vec<Sender<String>>,
 }
 impl IoChannels {
     pub fn new() -> Self {
@@ -138,6 +489,10 @@ impl Vm {
         Vm {
             data: Vec::new(),
             stack: Vec::new(),
+            globals: Vec::new(),
+            heap: Heap::new(),
+            call_stack: Vec::new(),
+            fp: 0,
             code: Vec::new(),
             ip: 0,
             num_ops: 0,
@@ -148,38 +503,173 @@ impl Vm {
         Vm {
             data: prog.data,
             stack: Vec::new(),
+            globals: Vec::new(),
+            heap: Heap::new(),
+            call_stack: Vec::new(),
+            fp: 0,
             code: prog.code,
             ip: 0,
             num_ops: 0,
             max_ops: None,
         }
     }
-    pub fn push(&mut self, v: i64) {
+    pub fn push(&mut self, v: Value) {
         self.stack.push(v);
     }
-    pub fn pop(&mut self) -> i64 {
-        self.stack.pop().unwrap()
+    pub fn pop(&mut self) -> Result<Value> {
+        self.stack
+            .pop()
+            .ok_or(Trap::StackUnderflow { offs: 0, len: 0 }.into())
     }
-    pub fn peek(&self) -> i64 {
-        *self.stack.last().unwrap()
+    pub fn pop_int(&mut self) -> Result<i64> {
+        match self.pop()? {
+            Value::Int(v) => Ok(v),
+            v => Err(type_mismatch("int", v).into()),
+        }
     }
-    pub fn peek_at(&self, offs: i64) -> i64 {
-        if offs >= 0 && (offs as usize) < self.stack.len() {
-            return *self
+    fn pop_bool(&mut self) -> Result<bool> {
+        match self.pop()? {
+            Value::Bool(v) => Ok(v),
+            v => Err(type_mismatch("bool", v).into()),
+        }
+    }
+    fn pop_str(&mut self) -> Result<&str> {
+        match self.pop()? {
+            Value::Str(r) => self.string(r),
+            v => Err(type_mismatch("string", v).into()),
+        }
+    }
+    fn pop_array(&mut self) -> Result<usize> {
+        match self.pop()? {
+            Value::Array(r) => Ok(r),
+            v => Err(type_mismatch("array", v).into()),
+        }
+    }
+    // All values that are still needed have to be on the stack (or in `object`) when allocating.
+    fn alloc(&mut self, object: Object) -> Result<usize> {
+        if self.heap.needs_collection() {
+            let roots = self
                 .stack
-                .get(self.stack.len() - 1 - offs as usize)
-                .unwrap();
+                .iter()
+                .chain(self.globals.iter().flatten())
+                .chain(object.references())
+                .copied();
+            self.heap.collect(roots);
+        }
+        Ok(self.heap.alloc(object)?)
+    }
+    fn string(&self, r: usize) -> Result<&str> {
+        match self.heap.get(r) {
+            Some(Object::Str(s)) => Ok(s),
+            _ => Err(Trap::InvalidReference(r).into()),
+        }
+    }
+    // Pops a record, returns its field at `offset`.
+    fn pop_field(&mut self, offset: i16) -> Result<&mut Value> {
+        let r = match self.pop()? {
+            Value::Record(r) => r,
+            v => return Err(type_mismatch("record", v).into()),
+        };
+        let fields = match self.heap.get_mut(r) {
+            Some(Object::Record(fields)) => fields,
+            _ => return Err(Trap::InvalidReference(r).into()),
+        };
+        let len = fields.len();
+        fields.get_mut(offset as usize).ok_or_else(|| {
+            Trap::IndexOutOfBounds {
+                index: offset as i64,
+                len,
+            }
+            .into()
+        })
+    }
+    fn array(&mut self, r: usize) -> Result<&mut Vec<Value>> {
+        match self.heap.get_mut(r) {
+            Some(Object::Array(elements)) => Ok(elements),
+            _ => Err(Trap::InvalidReference(r).into()),
+        }
+    }
+    // Pops an index and an array, returns the array and the checked index.
+    fn pop_index(&mut self) -> Result<(&mut Vec<Value>, usize)> {
+        let index = self.pop_int()?;
+        let r = self.pop_array()?;
+        let elements = self.array(r)?;
+        if index < 0 || index as usize >= elements.len() {
+            return Err(Trap::IndexOutOfBounds {
+                index,
+                len: elements.len(),
+            }
+            .into());
         }
-        panic!("stack underflow: {} (of {})", offs, self.stack.len());
+        Ok((elements, index as usize))
     }
-    pub fn peek_at_mut(&mut self, offs: i64) -> &mut i64 {
+    /// Text representation of a value, as written to the output channels.
+    pub fn render(&self, v: Value) -> Result<String> {
+        Ok(match v {
+            Value::Str(r) => self.string(r)?.to_string(),
+            Value::Function(_) => "<function>".to_string(),
+            Value::Array(r) | Value::Record(r) => {
+                let (open, close, values) = match self.heap.get(r) {
+                    Some(Object::Array(elements)) => ("[", "]", elements),
+                    Some(Object::Record(fields)) => ("{", "}", fields),
+                    _ => return Err(Trap::InvalidReference(r).into()),
+                };
+                let values: Result<Vec<_>> = values.iter().map(|v| self.render(*v)).collect();
+                format!("{}{}{}", open, values?.join(", "), close)
+            }
+            v => v.to_string(),
+        })
+    }
+    pub fn peek(&self) -> Result<Value> {
+        self.peek_at(0)
+    }
+    pub fn peek_at(&self, offs: i64) -> Result<Value> {
+        if offs >= 0 && (offs as usize) < self.stack.len() {
+            return Ok(self.stack[self.stack.len() - 1 - offs as usize]);
+        }
+        Err(Trap::StackUnderflow {
+            offs,
+            len: self.stack.len(),
+        }
+        .into())
+    }
+    pub fn peek_at_mut(&mut self, offs: i64) -> Result<&mut Value> {
         if offs >= 0 && (offs as usize) < self.stack.len() {
             let top = self.stack.len() - 1;
-            return self.stack.get_mut(top - offs as usize).unwrap();
+            return Ok(&mut self.stack[top - offs as usize]);
+        }
+        Err(Trap::StackUnderflow {
+            offs,
+            len: self.stack.len(),
+        }
+        .into())
+    }
+    /// The current instruction pointer followed by the return addresses of all active calls,
+    /// innermost first.
+    pub fn backtrace(&self) -> Vec<usize> {
+        std::iter::once(self.ip)
+            .chain(self.call_stack.iter().rev().map(|frame| frame.ret))
+            .collect()
+    }
+    fn local_index(&self, slot: i16) -> Result<usize> {
+        let index = self.fp as i64 + slot as i64;
+        if index < 0 || index as usize >= self.stack.len() {
+            return Err(Trap::InvalidLocal(slot as i64).into());
         }
-        panic!("stack underflow: {} (of {})", offs, self.stack.len());
+        Ok(index as usize)
     }
-    pub fn exec(&mut self, io: Option<&IoChannels>) {
+    fn jump_target(&self, dst: i64) -> Result<usize> {
+        let target = self.ip as i64 + dst;
+        if target < 0 || target as usize >= self.code.len() {
+            return Err(Trap::InvalidJump {
+                target,
+                len: self.code.len(),
+            }
+            .into());
+        }
+        Ok(target as usize)
+    }
+    pub fn exec(&mut self, io: Option<&IoChannels>) -> Result<()> {
         while self.ip < self.code.len() {
             let op = self.code[self.ip].clone();
             self.num_ops += 1;
@@ -191,74 +681,215 @@ impl Vm {
             }
             debug!("exec: {} {:?}", self.ip, op);
             match op {
-                Op::PushConst/*(offs)*/ => {
-                    let offs = self.pop();
-                    self.push(self.data[offs as usize]);
-                    // self.push(self.data[offs as usize]);
+                Op::PushConst => {
+                    let offs = self.pop_int()?;
+                    let v = match self.data.get(offs as usize) {
+                        Some(Constant::Int(v)) => Value::Int(*v),
+                        Some(Constant::Str(s)) => {
+                            let s = s.clone();
+                            Value::Str(self.alloc(Object::Str(s))?)
+                        }
+                        None => return Err(Trap::InvalidConst(offs).into()),
+                    };
+                    self.push(v);
+                }
+                Op::PushStack => {
+                    let offs = self.pop_int()?;
+                    self.push(self.peek_at(offs)?)
                 }
-                Op::PushStack/*(offs)*/ => {
-                    let offs = self.pop();
-                    self.push(self.peek_at(offs as i64))
-                
-                },
                 Op::Arith(op) => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    let c = op.eval(a, b);
-                    // debug!( "{} = {} {:?} {}", c, a, op, b);
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    let c = match (op, a, b) {
+                        // strings are compared by content
+                        (ArithOp::Equal, Value::Str(a), Value::Str(b)) => {
+                            Value::Bool(self.string(a)? == self.string(b)?)
+                        }
+                        (ArithOp::NotEqual, Value::Str(a), Value::Str(b)) => {
+                            Value::Bool(self.string(a)? != self.string(b)?)
+                        }
+                        _ => op.eval(a, b)?,
+                    };
                     self.push(c);
-
-                    // let b = self.pop();
-                    // let a = self.stack.last_mut().unwrap();
-                    // *a = op.eval(a.clone(), b);
                 }
-                Op::PushImmediate(v) => self.push(v as i64),
+                Op::Unary(op) => {
+                    let a = self.pop()?;
+                    self.push(op.eval(a)?);
+                }
+                Op::PushImmediate(v) => self.push(Value::Int(v as i64)),
                 Op::PushImmediate24(v) => {
                     let v: u32 = v.into();
-                    self.push(v as i64)
+                    self.push(Value::Int(v as i64))
                 }
-                Op::PushIp => self.push(self.ip as i64),
+                Op::PushBool(v) => self.push(Value::Bool(v)),
+                Op::PushIp => self.push(Value::Int(self.ip as i64)),
                 Op::Jmp(jmp_cond) => {
-                    let dst = self.pop();
+                    let dst = self.pop_int()?;
                     let cond = match jmp_cond {
                         Cond::Always => true,
-                        Cond::Zero => self.pop() == 0,
-                        Cond::NonZero => self.pop() != 0,
+                        Cond::Zero => !self.pop_bool()?,
+                        Cond::NonZero => self.pop_bool()?,
                     };
                     debug!("jmp: {} {}", cond, dst);
 
                     if cond {
-                        self.ip = (self.ip as i64 + dst) as usize;
-                        if self.ip >= self.code.len() {
-                            panic!(
-                                "jmp to invalid code location {} (of {})",
-                                self.ip,
-                                self.code.len()
-                            );
-                        }
+                        self.ip = self.jump_target(dst)?;
                         continue;
                     }
                 }
+                Op::Call => {
+                    let dst = self.pop_int()?;
+                    let target = self.jump_target(dst)?;
+                    self.call_stack.push(Frame {
+                        ret: self.ip + 1,
+                        fp: self.fp,
+                    });
+                    self.fp = self.stack.len();
+                    self.ip = target;
+                    continue;
+                }
+                Op::MakeClosure(n) => {
+                    let dst = self.pop_int()?;
+                    let address = self.jump_target(dst)?;
+                    let n = n as i64;
+                    if n < 0 || n as usize > self.stack.len() {
+                        return Err(Trap::StackUnderflow {
+                            offs: n,
+                            len: self.stack.len(),
+                        }
+                        .into());
+                    }
+                    let captures = self.stack.split_off(self.stack.len() - n as usize);
+                    let r = self.alloc(Object::Closure { address, captures })?;
+                    self.push(Value::Function(r));
+                }
+                Op::CallIndirect => {
+                    let r = match self.pop()? {
+                        Value::Function(r) => r,
+                        v => return Err(type_mismatch("function", v).into()),
+                    };
+                    let (address, captures) = match self.heap.get(r) {
+                        Some(Object::Closure { address, captures }) => (*address, captures),
+                        _ => return Err(Trap::InvalidReference(r).into()),
+                    };
+                    self.call_stack.push(Frame {
+                        ret: self.ip + 1,
+                        fp: self.fp,
+                    });
+                    self.fp = self.stack.len();
+                    self.stack.extend_from_slice(captures);
+                    self.ip = address;
+                    continue;
+                }
+                Op::Ret => {
+                    let frame = self.call_stack.pop().ok_or(Trap::ReturnWithoutCall)?;
+                    self.stack.truncate(self.fp);
+                    self.fp = frame.fp;
+                    self.ip = frame.ret;
+                    continue;
+                }
+                Op::LoadLocal(slot) => {
+                    let v = self.stack[self.local_index(slot)?];
+                    self.push(v);
+                }
+                Op::StoreLocal(slot) => {
+                    let v = self.pop()?;
+                    let index = self.local_index(slot)?;
+                    self.stack[index] = v;
+                }
+                Op::LoadGlobal(slot) => match self.globals.get(slot as usize) {
+                    Some(Some(v)) if slot >= 0 => {
+                        let v = *v;
+                        self.push(v);
+                    }
+                    _ => return Err(Trap::InvalidGlobal(slot as i64).into()),
+                },
+                Op::StoreGlobal(slot) => {
+                    let v = self.pop()?;
+                    if slot < 0 {
+                        return Err(Trap::InvalidGlobal(slot as i64).into());
+                    }
+                    let slot = slot as usize;
+                    if slot >= self.globals.len() {
+                        self.globals.resize(slot + 1, None);
+                    }
+                    self.globals[slot] = Some(v);
+                }
                 Op::Output(channel) => {
-                    let v = self.pop();
+                    let v = self.pop()?;
                     if let Some(io) = &io {
+                        let v = self.render(v)?;
                         debug!("output #{}: {}", channel, v);
-                        io.channels[channel as usize].send(v).unwrap();
+                        io.channels
+                            .get(channel as usize)
+                            .ok_or(Trap::InvalidChannel(channel))?
+                            .send(v)
+                            .map_err(|_| Trap::InvalidChannel(channel))?;
                     }
                 }
                 Op::Pop(PopMode::One) => {
-                    self.pop();
+                    self.pop()?;
                 }
                 Op::Pop(PopMode::Top) => {
-                    let n = self.pop();
+                    let n = self.pop_int()?;
                     for _ in 0..n {
-                        self.pop();
+                        self.pop()?;
                     }
                 }
                 Op::Move => {
-                    let offs = self.pop();
-                    let v = self.pop();
-                    *self.peek_at_mut(offs) = v;
+                    let offs = self.pop_int()?;
+                    let v = self.pop()?;
+                    *self.peek_at_mut(offs)? = v;
+                }
+                Op::Concat => {
+                    let b = self.pop_str()?.to_string();
+                    let a = self.pop_str()?;
+                    let s = Object::Str(a.to_string() + &b);
+                    let r = self.alloc(s)?;
+                    self.push(Value::Str(r));
+                }
+                Op::Len => {
+                    let len = match self.pop()? {
+                        Value::Str(r) => self.string(r)?.chars().count(),
+                        Value::Array(r) => self.array(r)?.len(),
+                        v => return Err(type_mismatch("string or array", v).into()),
+                    };
+                    self.push(Value::Int(len as i64));
+                }
+                Op::NewArray | Op::NewRecord => {
+                    let n = self.pop_int()?;
+                    if n < 0 || n as usize > self.stack.len() {
+                        return Err(Trap::StackUnderflow {
+                            offs: n,
+                            len: self.stack.len(),
+                        }
+                        .into());
+                    }
+                    let values = self.stack.split_off(self.stack.len() - n as usize);
+                    let v = if let Op::NewArray = op {
+                        Value::Array(self.alloc(Object::Array(values))?)
+                    } else {
+                        Value::Record(self.alloc(Object::Record(values))?)
+                    };
+                    self.push(v);
+                }
+                Op::LoadField(offset) => {
+                    let v = *self.pop_field(offset)?;
+                    self.push(v);
+                }
+                Op::StoreField(offset) => {
+                    let v = self.pop()?;
+                    *self.pop_field(offset)? = v;
+                }
+                Op::LoadIndex => {
+                    let (elements, index) = self.pop_index()?;
+                    let v = elements[index];
+                    self.push(v);
+                }
+                Op::StoreIndex => {
+                    let v = self.pop()?;
+                    let (elements, index) = self.pop_index()?;
+                    elements[index] = v;
                 }
                 Op::Noop => (),
                 Op::Break => {
@@ -267,6 +898,7 @@ impl Vm {
             }
             self.ip += 1;
         }
+        Ok(())
     }
 }
 
@@ -279,9 +911,9 @@ mod tests {
     #[test]
     fn arith() {
         let mut prog = Program::new();
-        prog.data.push(123);
-        prog.data.push(666);
-        prog.data.push(777);
+        prog.data.push(Constant::Int(123));
+        prog.data.push(Constant::Int(666));
+        prog.data.push(Constant::Int(777));
 
         prog.code.push(Op::PushImmediate(0));
         prog.code.push(Op::PushConst);
@@ -307,7 +939,7 @@ mod tests {
         let mut io = IoChannels::new();
         io.channels.push(sender);
         let mut vm = Vm::from_program(prog);
-        vm.exec(Some(&io));
+        vm.exec(Some(&io)).unwrap();
         println!(
             "sub: {} add: {}",
             receiver.recv().unwrap(),
@@ -321,8 +953,8 @@ mod tests {
         assert_eq!(std::mem::size_of::<Op>(), std::mem::size_of::<u32>());
         unsafe {
             let mut y = [Op::Arith(ArithOp::Add); 11]; // = vm.code[0..6];
-                                                      // std::slice::bytes::copy_memory(&vm.code, &mut y);
-                                                      // let x = std::mem::transmute::<[Op; 6], [u8; 6 * 4]>(y);
+                                                       // std::slice::bytes::copy_memory(&vm.code, &mut y);
+                                                       // let x = std::mem::transmute::<[Op; 6], [u8; 6 * 4]>(y);
             y.clone_from_slice(&vm.code[..]);
             let _z: [u8; 11 * 4] = std::mem::transmute_copy(&y);
             // println!("z: {:?}", z);
@@ -350,12 +982,12 @@ mod tests {
         prog.code.push(Op::Arith(ArithOp::Div));
 
         let mut vm = Vm::from_program(prog);
-        vm.exec(None);
+        vm.exec(None).unwrap();
 
-        assert_eq!(vm.pop(), 2);
-        assert_eq!(vm.pop(), 12);
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 3);
+        assert_eq!(vm.pop_int().unwrap(), 2);
+        assert_eq!(vm.pop_int().unwrap(), 12);
+        assert_eq!(vm.pop_int().unwrap(), 1);
+        assert_eq!(vm.pop_int().unwrap(), 3);
     }
     #[test]
     fn arith_eq() {
@@ -377,13 +1009,18 @@ mod tests {
         prog.code.push(Op::PushImmediate(2));
         prog.code.push(Op::Arith(ArithOp::NotEqual));
 
+        prog.code.push(Op::PushBool(true));
+        prog.code.push(Op::PushBool(true));
+        prog.code.push(Op::Arith(ArithOp::Equal));
+
         let mut vm = Vm::from_program(prog);
-        vm.exec(None);
+        vm.exec(None).unwrap();
 
-        assert_eq!(vm.pop(), 0);
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 0);
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
     }
     #[test]
     fn arith_rel() {
@@ -413,75 +1050,95 @@ mod tests {
         prog.code.push(Op::PushImmediate(2));
         prog.code.push(Op::Arith(ArithOp::LessEqual));
 
+        prog.code.push(Op::PushImmediate(3));
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::Arith(ArithOp::GreaterThan));
+
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::Arith(ArithOp::GreaterThan));
+
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::Arith(ArithOp::GreaterEqual));
+
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::PushImmediate(3));
+        prog.code.push(Op::Arith(ArithOp::GreaterEqual));
+
         let mut vm = Vm::from_program(prog);
-        vm.exec(None);
+        vm.exec(None).unwrap();
 
-        assert_eq!(vm.pop(), 0);
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 0);
-        assert_eq!(vm.pop(), 0);
-        assert_eq!(vm.pop(), 1);
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
     }
     #[test]
     fn arith_bool() {
         let mut prog = Program::new();
 
-        prog.code.push(Op::PushImmediate(0));
-        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushBool(false));
+        prog.code.push(Op::PushBool(false));
         prog.code.push(Op::Arith(ArithOp::And));
 
-        prog.code.push(Op::PushImmediate(1));
-        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushBool(true));
+        prog.code.push(Op::PushBool(false));
         prog.code.push(Op::Arith(ArithOp::And));
 
-        prog.code.push(Op::PushImmediate(0));
-        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushBool(false));
+        prog.code.push(Op::PushBool(true));
         prog.code.push(Op::Arith(ArithOp::And));
 
-        prog.code.push(Op::PushImmediate(1));
-        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushBool(true));
+        prog.code.push(Op::PushBool(true));
         prog.code.push(Op::Arith(ArithOp::And));
 
-        prog.code.push(Op::PushImmediate(0));
-        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushBool(false));
+        prog.code.push(Op::PushBool(false));
         prog.code.push(Op::Arith(ArithOp::Or));
 
-        prog.code.push(Op::PushImmediate(1));
-        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushBool(true));
+        prog.code.push(Op::PushBool(false));
         prog.code.push(Op::Arith(ArithOp::Or));
 
-        prog.code.push(Op::PushImmediate(0));
-        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushBool(false));
+        prog.code.push(Op::PushBool(true));
         prog.code.push(Op::Arith(ArithOp::Or));
 
-        prog.code.push(Op::PushImmediate(1));
-        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushBool(true));
+        prog.code.push(Op::PushBool(true));
         prog.code.push(Op::Arith(ArithOp::Or));
 
         let mut vm = Vm::from_program(prog);
-        vm.exec(None);
+        vm.exec(None).unwrap();
 
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 0);
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
 
-        assert_eq!(vm.pop(), 1);
-        assert_eq!(vm.pop(), 0);
-        assert_eq!(vm.pop(), 0);
-        assert_eq!(vm.pop(), 0);
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
     }
     #[test]
     fn jump() {
-        env_logger::init();
+        let _ = env_logger::try_init();
         info!("log");
         let mut prog = Program::new();
-        prog.data.push(123);
-        prog.data.push(666);
-        prog.data.push(777);
+        prog.data.push(Constant::Int(123));
+        prog.data.push(Constant::Int(666));
+        prog.data.push(Constant::Int(777));
 
-        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushBool(true));
         prog.code.push(Op::PushImmediate(5));
         prog.code.push(Op::Jmp(Cond::NonZero));
         prog.code.push(Op::PushImmediate(2));
@@ -494,8 +1151,340 @@ mod tests {
         println!("{}", serde_yaml::to_string(&prog).unwrap());
 
         let mut vm = Vm::from_program(prog);
-        vm.exec(None);
-        println!("{}", vm.pop());
+        vm.exec(None).unwrap();
+        println!("{}", vm.pop().unwrap());
+    }
+    #[test]
+    fn arith_bits() {
+        let mut prog = Program::new();
+        let ops = [
+            (7, 3, ArithOp::Mod),
+            (-7, 3, ArithOp::Mod),
+            (0b1100, 0b1010, ArithOp::BitAnd),
+            (0b1100, 0b1010, ArithOp::BitOr),
+            (0b1100, 0b1010, ArithOp::BitXor),
+            (1, 4, ArithOp::Shl),
+            (-16, 2, ArithOp::Shr),
+            (-16, 60, ArithOp::Ushr),
+        ];
+        for (a, b, op) in ops.iter() {
+            prog.code.push(Op::PushImmediate(*a));
+            prog.code.push(Op::PushImmediate(*b));
+            prog.code.push(Op::Arith(*op));
+        }
+        let mut vm = Vm::from_program(prog);
+        vm.exec(None).unwrap();
+
+        assert_eq!(vm.pop_int().unwrap(), 0xF);
+        assert_eq!(vm.pop_int().unwrap(), -4);
+        assert_eq!(vm.pop_int().unwrap(), 16);
+        assert_eq!(vm.pop_int().unwrap(), 0b0110);
+        assert_eq!(vm.pop_int().unwrap(), 0b1110);
+        assert_eq!(vm.pop_int().unwrap(), 0b1000);
+        assert_eq!(vm.pop_int().unwrap(), -1);
+        assert_eq!(vm.pop_int().unwrap(), 1);
+    }
+    #[test]
+    fn unary() {
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(5));
+        prog.code.push(Op::Unary(UnaryOp::Neg));
+        prog.code.push(Op::PushBool(false));
+        prog.code.push(Op::Unary(UnaryOp::Not));
+        prog.code.push(Op::PushBool(true));
+        prog.code.push(Op::Unary(UnaryOp::Not));
+        prog.code.push(Op::PushImmediate(0b1010));
+        prog.code.push(Op::Unary(UnaryOp::Complement));
+
+        let mut vm = Vm::from_program(prog);
+        vm.exec(None).unwrap();
+        assert_eq!(vm.pop_int().unwrap(), -11);
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop().unwrap(), Value::Bool(true));
+        assert_eq!(vm.pop_int().unwrap(), -5);
+    }
+    #[test]
+    fn strings() {
+        let mut prog = Program::new();
+        prog.data.push(Constant::Str("foo".into()));
+        prog.data.push(Constant::Str("bär".into()));
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushConst);
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushConst);
+        prog.code.push(Op::Concat);
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushStack);
+        prog.code.push(Op::Len);
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushStack);
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushConst);
+        prog.code.push(Op::Arith(ArithOp::Equal));
+
+        let mut vm = Vm::from_program(prog);
+        vm.exec(None).unwrap();
+        assert_eq!(vm.pop().unwrap(), Value::Bool(false));
+        assert_eq!(vm.pop_int().unwrap(), 6);
+        assert_eq!(vm.pop_str().unwrap(), "foobär");
+    }
+    #[test]
+    fn arrays() {
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(10));
+        prog.code.push(Op::PushImmediate(20));
+        prog.code.push(Op::PushImmediate(30));
+        prog.code.push(Op::PushImmediate(3));
+        prog.code.push(Op::NewArray);
+        // a[1] = a[2] + 1
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushStack);
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushStack);
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::LoadIndex);
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::Arith(ArithOp::Add));
+        prog.code.push(Op::StoreIndex);
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushStack);
+        prog.code.push(Op::Len);
+
+        let mut vm = Vm::from_program(prog);
+        vm.exec(None).unwrap();
+        assert_eq!(vm.pop_int().unwrap(), 3);
+        let a = vm.pop().unwrap();
+        assert_eq!(vm.render(a).unwrap(), "[10, 31, 30]");
+    }
+    #[test]
+    fn records() {
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::NewRecord);
+        // r.0 = r.1 * 5
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushStack);
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushStack);
+        prog.code.push(Op::LoadField(1));
+        prog.code.push(Op::PushImmediate(5));
+        prog.code.push(Op::Arith(ArithOp::Mul));
+        prog.code.push(Op::StoreField(0));
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushStack);
+        prog.code.push(Op::LoadField(2));
+
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(
+            vm.exec(None),
+            Err(Trap::IndexOutOfBounds { index: 2, len: 2 }.into())
+        );
+        let r = vm.pop().unwrap();
+        assert_eq!(vm.render(r).unwrap(), "{10, 2}");
+    }
+    #[test]
+    fn call_ret() {
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(0)); // result slot
+        prog.code.push(Op::PushImmediate(7)); // argument
+        prog.code.push(Op::PushImmediate(2));
+        prog.code.push(Op::Call);
+        prog.code.push(Op::Break);
+        prog.code.push(Op::LoadLocal(-1));
+        prog.code.push(Op::PushImmediate(35));
+        prog.code.push(Op::Arith(ArithOp::Add));
+        prog.code.push(Op::StoreLocal(-2));
+        prog.code.push(Op::PushImmediate(123)); // local, dropped by ret
+        prog.code.push(Op::Ret);
+
+        let mut vm = Vm::from_program(prog);
+        vm.exec(None).unwrap();
+        assert_eq!(vm.pop_int().unwrap(), 7);
+        assert_eq!(vm.pop_int().unwrap(), 42);
+        assert_eq!(
+            vm.pop(),
+            Err(Trap::StackUnderflow { offs: 0, len: 0 }.into())
+        );
+        assert_eq!(vm.backtrace(), [4]);
+
+        let mut prog = Program::new();
+        prog.code.push(Op::Ret);
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(vm.exec(None), Err(Trap::ReturnWithoutCall.into()));
+    }
+    #[test]
+    fn closures() {
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(0)); // result slot
+        prog.code.push(Op::PushImmediate(7)); // argument
+        prog.code.push(Op::PushImmediate(35)); // captured value
+        prog.code.push(Op::PushImmediate(4));
+        prog.code.push(Op::MakeClosure(1));
+        prog.code.push(Op::CallIndirect);
+        prog.code.push(Op::Break);
+        prog.code.push(Op::Noop);
+        prog.code.push(Op::LoadLocal(-1));
+        prog.code.push(Op::LoadLocal(0));
+        prog.code.push(Op::Arith(ArithOp::Add));
+        prog.code.push(Op::StoreLocal(-2));
+        prog.code.push(Op::Ret);
+
+        let mut vm = Vm::from_program(prog);
+        vm.exec(None).unwrap();
+        assert_eq!(vm.pop_int().unwrap(), 7);
+        assert_eq!(vm.pop_int().unwrap(), 42);
+        assert_eq!(vm.heap.live(), 1);
+
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::CallIndirect);
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(
+            vm.exec(None),
+            Err(Trap::TypeMismatch {
+                expected: "function",
+                found: "int"
+            }
+            .into())
+        );
+    }
+    #[test]
+    fn globals() {
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(7));
+        prog.code.push(Op::StoreGlobal(2));
+        prog.code.push(Op::LoadGlobal(2));
+        prog.code.push(Op::LoadGlobal(2));
+        prog.code.push(Op::Arith(ArithOp::Mul));
+        prog.code.push(Op::StoreGlobal(0));
+        prog.code.push(Op::LoadGlobal(0));
+        prog.code.push(Op::LoadGlobal(1));
+
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(vm.exec(None), Err(Trap::InvalidGlobal(1).into()));
+        assert_eq!(vm.pop_int().unwrap(), 49);
+        assert_eq!(
+            vm.globals,
+            [Some(Value::Int(49)), None, Some(Value::Int(7))]
+        );
+    }
+    #[test]
+    fn traps() {
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::Arith(ArithOp::Div));
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(vm.exec(None), Err(Trap::DivisionByZero.into()));
+
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushStack);
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(
+            vm.exec(None),
+            Err(Trap::StackUnderflow { offs: 1, len: 0 }.into())
+        );
+
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(-5));
+        prog.code.push(Op::Jmp(Cond::Always));
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(
+            vm.exec(None),
+            Err(Trap::InvalidJump { target: -4, len: 2 }.into())
+        );
+
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushBool(true));
+        prog.code.push(Op::Arith(ArithOp::Add));
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(
+            vm.exec(None),
+            Err(Trap::TypeMismatch {
+                expected: "int",
+                found: "bool"
+            }
+            .into())
+        );
+
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::Jmp(Cond::NonZero));
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(
+            vm.exec(None),
+            Err(Trap::TypeMismatch {
+                expected: "bool",
+                found: "int"
+            }
+            .into())
+        );
+
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::Len);
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(
+            vm.exec(None),
+            Err(Trap::TypeMismatch {
+                expected: "string or array",
+                found: "int"
+            }
+            .into())
+        );
+
+        let mut prog = Program::new();
+        prog.code.push(Op::PushImmediate(7));
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::NewArray);
+        prog.code.push(Op::PushImmediate(1));
+        prog.code.push(Op::LoadIndex);
+        let mut vm = Vm::from_program(prog);
+        assert_eq!(
+            vm.exec(None),
+            Err(Trap::IndexOutOfBounds { index: 1, len: 1 }.into())
+        );
+    }
+    #[test]
+    fn l1b() {
+        let mut prog = Program::new();
+        prog.data.push(Constant::Str("hi".into()));
+        prog.code.push(Op::PushImmediate(0));
+        prog.code.push(Op::PushConst);
+        prog.code.push(Op::Output(0));
+        prog.symbols.push(("start".into(), 0));
+        prog.source = Some("hi.l1".into());
+        let bytes = prog.to_bytes();
+        assert!(is_l1b(&bytes));
+        let read = Program::from_bytes(&bytes).unwrap();
+        assert_eq!(read.data, prog.data);
+        assert_eq!(read.code, prog.code);
+        assert_eq!(read.symbols, prog.symbols);
+        assert_eq!(read.source, prog.source);
+        assert_eq!(read.symbolize(2), Some(("start", 2)));
+
+        // symbols and debug info are optional
+        let bare = Program::from_bytes(&Program::new().to_bytes()).unwrap();
+        assert!(bare.code.is_empty() && bare.symbols.is_empty() && bare.source.is_none());
+
+        let error = |bytes: &[u8]| match Program::from_bytes(bytes) {
+            Err(Error::Format(msg)) => msg,
+            _ => panic!("invalid file accepted"),
+        };
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(error(&newer), "unsupported format version 2 (expected 1)");
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(error(&corrupt), "checksum mismatch");
+        assert_eq!(error(&bytes[..10]), "not an l1b file");
+        assert_eq!(error(b"data: []"), "not an l1b file");
     }
     #[test]
     fn int24() {
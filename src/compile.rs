@@ -0,0 +1,1117 @@
+use crate::{
+    asm,
+    ast::{
+        Declaration, Expr, ExprKind, Ident, Opcode, Span, SpannedIdent, Stmt, StmtKind, Toplevel,
+        Type, UnOp,
+    },
+    bytecode::Program,
+    error::{Error, Result},
+    lang1, module,
+    typeck::{self, TypeInfo},
+};
+use handy::HandleMap;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+
+// Bindings are resolved to fixed slots relative to the VM's frame pointer: locals count up from 0,
+// function arguments are stored right below the frame (at negative slots).
+struct StackFrame {
+    bindings: HashMap<Ident, i64>,
+    stack_top: usize,
+    bindings_top: usize,
+}
+
+impl StackFrame {
+    fn new(stack_top: usize) -> StackFrame {
+        StackFrame {
+            bindings: HashMap::new(),
+            stack_top,
+            bindings_top: stack_top,
+        }
+    }
+}
+
+struct ScopeStack {
+    frames: Vec<StackFrame>,
+}
+
+impl ScopeStack {
+    fn new() -> ScopeStack {
+        ScopeStack {
+            frames: vec![StackFrame::new(0)],
+        }
+    }
+
+    fn push_frame(&mut self) {
+        let top = self.frames.last().unwrap().stack_top;
+        self.frames.push(StackFrame::new(top));
+    }
+    fn pop_frame(&mut self) -> usize {
+        let top = self.frames.last().unwrap().stack_top;
+        self.frames.pop();
+        let new_top = self.frames.last().unwrap().stack_top;
+        assert!(top >= new_top);
+        top - new_top
+    }
+    fn add_binding(&mut self, ident: Ident) {
+        let frame = self.frames.last_mut().unwrap();
+        assert!(frame.stack_top > 0);
+        frame.bindings.insert(ident, frame.stack_top as i64 - 1);
+        // frame.stack_top += 1;
+        debug!(
+            "add binding: {:?} {} -> {}",
+            ident,
+            frame.bindings_top,
+            frame.stack_top - 1
+        );
+
+        frame.bindings_top = frame.stack_top - 1;
+    }
+    fn add_argument(&mut self, ident: Ident, slot: i64) {
+        assert!(slot < 0);
+        debug!("add argument: {:?} -> {}", ident, slot);
+        self.frames.last_mut().unwrap().bindings.insert(ident, slot);
+    }
+    fn push_local(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+
+        debug!("push local {} -> {}", frame.stack_top, frame.stack_top + 1);
+        frame.stack_top += 1;
+    }
+    fn pop_local(&mut self, num: usize) {
+        let frame = self.frames.last_mut().unwrap();
+        debug!(
+            "pop local {} -> {}",
+            frame.stack_top,
+            frame.stack_top as i64 - num as i64
+        );
+
+        assert!(frame.stack_top - num >= frame.bindings_top);
+        frame.stack_top -= num;
+    }
+    fn stack_top(&self) -> usize {
+        self.frames.last().unwrap().stack_top
+    }
+    fn resolve(&self, ident: &Ident) -> Option<i64> {
+        for frame in self.frames.iter().rev() {
+            if let Some(slot) = frame.bindings.get(ident) {
+                debug!("resolve local: {:?} -> {}", ident, slot);
+                return Some(*slot);
+            }
+        }
+        None
+    }
+}
+
+// Jump targets of an enclosing loop, and the stack height `break` / `continue` have to pop back to.
+struct Loop {
+    continue_label: String,
+    break_label: String,
+    stack_top: usize,
+}
+
+struct CodeGen<'env> {
+    scopes: ScopeStack,
+    loops: Vec<Loop>,
+    asm_out: Vec<asm::Stmt>,
+    label_count: HashMap<String, usize>,
+    functions: HashMap<Ident, usize>,
+    // slots of the globals, i.e. the bindings of the toplevel scope
+    globals: HashMap<Ident, i64>,
+    // globals bound so far by the toplevel statements
+    defined_globals: HashSet<Ident>,
+    // declared fields of each struct
+    structs: HashMap<Ident, Vec<Ident>>,
+    // number of arguments of the function being compiled
+    function_args: Option<usize>,
+    // type checker results, used to pick the instruction for overloaded operators and to find the
+    // offsets of fields
+    types: TypeInfo,
+    env: &'env HandleMap<&'env str>,
+}
+
+impl<'env> CodeGen<'env> {
+    fn new(env: &'env HandleMap<&'env str>) -> CodeGen<'env> {
+        CodeGen {
+            scopes: ScopeStack::new(),
+            loops: Vec::new(),
+            asm_out: Vec::new(),
+            label_count: HashMap::new(),
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+            defined_globals: HashSet::new(),
+            structs: HashMap::new(),
+            function_args: None,
+            types: TypeInfo::default(),
+            env,
+        }
+    }
+    fn alloc_label(&mut self, template: &str) -> String {
+        let count = self.label_count.entry(template.into()).or_insert(0);
+        let c = *count;
+        *count += 1;
+        format!("{}{}", template, c)
+    }
+    fn emit(&mut self, stmt: &Stmt) -> Result<()> {
+        match &stmt.node {
+            StmtKind::LetBinding(ident, expr) => {
+                // self.bindings.insert(ident.clone(), self.stack_top);
+                self.emit_expr(expr)?;
+                if self.function_args.is_none() && self.scopes.frames.len() == 1 {
+                    self.asm_out
+                        .push(asm::Stmt::StoreGlobal(self.globals[&ident.node]));
+                    self.scopes.pop_local(1);
+                    self.defined_globals.insert(ident.node);
+                } else {
+                    self.scopes.add_binding(ident.node);
+                }
+            }
+            StmtKind::Assign(ident, expr, op) => {
+                let (load, store) = match self.scopes.resolve(&ident.node) {
+                    Some(slot) => (asm::Stmt::LoadLocal(slot), asm::Stmt::StoreLocal(slot)),
+                    None => match self.global(&ident.node) {
+                        Some(slot) => (asm::Stmt::LoadGlobal(slot), asm::Stmt::StoreGlobal(slot)),
+                        None => return Err(self.unknown_binding(ident.node, ident.span)),
+                    },
+                };
+                if let Some(op) = op {
+                    self.asm_out.push(load);
+                    self.scopes.push_local();
+                    self.emit_expr(expr)?;
+                    self.asm_out.push(self.binary_op(*op, expr));
+                    self.scopes.pop_local(1);
+                } else {
+                    self.emit_expr(expr)?;
+                }
+                self.asm_out.push(store);
+                self.scopes.pop_local(1);
+            }
+            StmtKind::AssignIndex(array, index, expr, op) => {
+                self.emit_expr(array)?;
+                self.emit_expr(index)?;
+                if let Some(op) = op {
+                    // load the element through copies of the array and the index
+                    for _ in 0..2 {
+                        self.asm_out.push(asm::Stmt::PushStack(1));
+                        self.scopes.push_local();
+                    }
+                    self.asm_out.push(asm::Stmt::LoadIndex);
+                    self.scopes.pop_local(1);
+                    self.emit_expr(expr)?;
+                    self.asm_out.push(self.binary_op(*op, expr));
+                    self.scopes.pop_local(1);
+                } else {
+                    self.emit_expr(expr)?;
+                }
+                self.asm_out.push(asm::Stmt::StoreIndex);
+                self.scopes.pop_local(3);
+            }
+            StmtKind::AssignField(record, field, expr, op) => {
+                let offset = self.field_offset(field)?;
+                self.emit_expr(record)?;
+                if let Some(op) = op {
+                    self.asm_out.push(asm::Stmt::PushStack(0));
+                    self.asm_out.push(asm::Stmt::LoadField(offset));
+                    self.scopes.push_local();
+                    self.emit_expr(expr)?;
+                    self.asm_out.push(self.binary_op(*op, expr));
+                    self.scopes.pop_local(1);
+                } else {
+                    self.emit_expr(expr)?;
+                }
+                self.asm_out.push(asm::Stmt::StoreField(offset));
+                self.scopes.pop_local(2);
+            }
+            StmtKind::IfElse(expr, if_stmt, None) => {
+                self.emit_expr(expr)?;
+                let label = self.alloc_label("if_end");
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Zero, Some(label.clone())));
+                self.scopes.pop_local(1);
+                self.emit(if_stmt)?;
+                self.asm_out.push(asm::Stmt::Label(label));
+            }
+            StmtKind::IfElse(expr, if_stmt, Some(else_stmt)) => {
+                self.emit_expr(expr)?;
+                let else_label = self.alloc_label("else");
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Zero, Some(else_label.clone())));
+                self.scopes.pop_local(1);
+                let dbg_label = self.alloc_label("if_else_begin");
+                self.asm_out.push(asm::Stmt::Label(dbg_label));
+
+                self.emit(if_stmt)?;
+                let end_label = self.alloc_label("else_end");
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Always, Some(end_label.clone())));
+                self.asm_out.push(asm::Stmt::Label(else_label));
+                self.emit(else_stmt)?;
+                self.asm_out.push(asm::Stmt::Label(end_label));
+            }
+            StmtKind::While(expr, body) => {
+                let start_label = self.alloc_label("while");
+                self.asm_out.push(asm::Stmt::Label(start_label.clone()));
+                self.emit_expr(expr)?;
+                let exit_label = self.alloc_label("while_end");
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Zero, Some(exit_label.clone())));
+                self.scopes.pop_local(1);
+
+                self.emit_loop_body(body, start_label.clone(), exit_label.clone())?;
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Always, Some(start_label)));
+                self.asm_out.push(asm::Stmt::Label(exit_label));
+            }
+            StmtKind::For(ident, start, end, body) => {
+                // the counter and the end value live in a scope of their own
+                self.scopes.push_frame();
+                self.emit_expr(start)?;
+                self.scopes.add_binding(ident.node);
+                let counter = self.scopes.resolve(&ident.node).unwrap();
+                self.emit_expr(end)?;
+                let end_slot = self.scopes.stack_top() as i64 - 1;
+
+                let start_label = self.alloc_label("for");
+                let next_label = self.alloc_label("for_next");
+                let exit_label = self.alloc_label("for_end");
+                self.asm_out.push(asm::Stmt::Label(start_label.clone()));
+                self.asm_out.push(asm::Stmt::LoadLocal(counter));
+                self.asm_out.push(asm::Stmt::LoadLocal(end_slot));
+                self.asm_out.push(asm::Stmt::Arith(asm::ArithOp::LessThan));
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Zero, Some(exit_label.clone())));
+
+                self.emit_loop_body(body, next_label.clone(), exit_label.clone())?;
+                self.asm_out.push(asm::Stmt::Label(next_label));
+                self.asm_out.push(asm::Stmt::LoadLocal(counter));
+                self.asm_out.push(asm::Stmt::PushInline(1));
+                self.asm_out.push(asm::Stmt::Arith(asm::ArithOp::Add));
+                self.asm_out.push(asm::Stmt::StoreLocal(counter));
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Always, Some(start_label)));
+                self.asm_out.push(asm::Stmt::Label(exit_label));
+                let num_pop = self.scopes.pop_frame();
+                self.asm_out.push(asm::Stmt::Pop(num_pop as i64));
+            }
+            StmtKind::Break | StmtKind::Continue => {
+                let (label, stack_top) = match (&stmt.node, self.loops.last()) {
+                    (StmtKind::Break, Some(l)) => (l.break_label.clone(), l.stack_top),
+                    (_, Some(l)) => (l.continue_label.clone(), l.stack_top),
+                    (StmtKind::Break, None) => {
+                        return Err(Error::Resolve("break outside of loop".into(), stmt.span))
+                    }
+                    (_, None) => {
+                        return Err(Error::Resolve("continue outside of loop".into(), stmt.span))
+                    }
+                };
+                // drop the locals of all blocks entered since the start of the loop body
+                let num_pop = self.scopes.stack_top() - stack_top;
+                if num_pop > 0 {
+                    self.asm_out.push(asm::Stmt::Pop(num_pop as i64));
+                }
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Always, Some(label)));
+            }
+            StmtKind::Block(stmts) => {
+                self.scopes.push_frame();
+                for s in stmts {
+                    self.emit(s)?;
+                }
+                let num_pop = self.scopes.pop_frame();
+                debug!("scope exit: {}", num_pop);
+
+                self.asm_out.push(asm::Stmt::Pop(num_pop as i64));
+            }
+            StmtKind::Print(exprs) => {
+                for e in exprs {
+                    self.emit_expr(e)?;
+                    self.asm_out.push(asm::Stmt::Output(0));
+                    self.scopes.pop_local(1);
+                }
+            }
+            StmtKind::Call(expr) => {
+                self.emit_expr(expr)?;
+                self.asm_out.push(asm::Stmt::Pop(1));
+                self.scopes.pop_local(1);
+            }
+            StmtKind::Return(e) => {
+                let num_args = match self.function_args {
+                    Some(num_args) => num_args,
+                    None => {
+                        return Err(Error::Resolve(
+                            "return outside of function".into(),
+                            stmt.span,
+                        ))
+                    }
+                };
+                self.emit_expr(e)?;
+                // the result slot is right below the first argument. Locals are dropped by ret.
+                self.asm_out
+                    .push(asm::Stmt::StoreLocal(-(num_args as i64) - 1));
+                self.scopes.pop_local(1);
+                self.asm_out.push(asm::Stmt::Ret);
+            }
+        }
+        Ok(())
+    }
+    fn emit_expr(&mut self, expr: &Expr) -> Result<()> {
+        match &expr.node {
+            ExprKind::Number(v) => {
+                self.asm_out.push(asm::Stmt::PushInline(*v));
+                self.scopes.push_local();
+            }
+            ExprKind::Array(elements) => {
+                for e in elements {
+                    self.emit_expr(e)?;
+                }
+                self.asm_out
+                    .push(asm::Stmt::NewArray(elements.len() as i64));
+                self.scopes.pop_local(elements.len());
+                self.scopes.push_local();
+            }
+            ExprKind::Index(array, index) => {
+                self.emit_expr(array)?;
+                self.emit_expr(index)?;
+                self.asm_out.push(asm::Stmt::LoadIndex);
+                self.scopes.pop_local(1);
+            }
+            ExprKind::Struct(name, fields) => {
+                // the values are evaluated in the order of the declaration, which is the layout
+                // of the record
+                let declared = match self.structs.get(&name.node) {
+                    Some(declared) => declared.clone(),
+                    None => return Err(self.unknown_binding(name.node, name.span)),
+                };
+                for ident in &declared {
+                    match fields.iter().find(|(field, _)| field.node == *ident) {
+                        Some((_, e)) => self.emit_expr(e)?,
+                        None => {
+                            return Err(Error::Resolve(
+                                format!("missing field `{}`", self.env.get(*ident).unwrap()),
+                                name.span,
+                            ))
+                        }
+                    }
+                }
+                self.asm_out
+                    .push(asm::Stmt::NewRecord(declared.len() as i64));
+                self.scopes.pop_local(declared.len());
+                self.scopes.push_local();
+            }
+            ExprKind::Field(record, field) => {
+                let offset = self.field_offset(field)?;
+                self.emit_expr(record)?;
+                self.asm_out.push(asm::Stmt::LoadField(offset));
+            }
+            ExprKind::EnvLoad(ident) => {
+                if let Some(slot) = self.scopes.resolve(ident) {
+                    self.asm_out.push(asm::Stmt::LoadLocal(slot));
+                } else if let Some(slot) = self.global(ident) {
+                    self.asm_out.push(asm::Stmt::LoadGlobal(slot));
+                } else if self.functions.contains_key(ident) {
+                    // a declared function as a value, it captures nothing
+                    let label = format!("func_{}", self.env.get(*ident).unwrap());
+                    self.asm_out.push(asm::Stmt::Closure(label, 0));
+                } else {
+                    return Err(self.unknown_binding(*ident, expr.span));
+                }
+                self.scopes.push_local();
+            }
+            ExprKind::Function(args, body) => {
+                let captures = self
+                    .types
+                    .captures
+                    .get(&expr.span)
+                    .cloned()
+                    .unwrap_or_default();
+                let label = self.alloc_label("lambda");
+                let end_label = self.alloc_label("lambda_end");
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Always, Some(end_label.clone())));
+                self.emit_function(label.clone(), args, &captures, body)?;
+                self.asm_out.push(asm::Stmt::Label(end_label));
+                for ident in &captures {
+                    match self.scopes.resolve(ident) {
+                        Some(slot) => self.asm_out.push(asm::Stmt::LoadLocal(slot)),
+                        None => return Err(self.unknown_binding(*ident, expr.span)),
+                    }
+                    self.scopes.push_local();
+                }
+                self.asm_out
+                    .push(asm::Stmt::Closure(label, captures.len() as i64));
+                self.scopes.pop_local(captures.len());
+                self.scopes.push_local();
+            }
+            ExprKind::Bool(v) => {
+                self.asm_out.push(asm::Stmt::PushBool(*v));
+                self.scopes.push_local();
+            }
+            ExprKind::Str(s) => {
+                self.asm_out.push(asm::Stmt::PushString(s.clone()));
+                self.scopes.push_local();
+            }
+            ExprKind::Op(a, op @ (Opcode::And | Opcode::Or), b) => {
+                // only evaluate the right operand if the left one does not decide the result. Both
+                // operands go through a conditional jump, so the VM checks that they are booleans.
+                let (cond, short_value, short_label, end_label) = if let Opcode::And = op {
+                    (asm::Cond::Zero, false, "and_false", "and_end")
+                } else {
+                    (asm::Cond::NonZero, true, "or_true", "or_end")
+                };
+                let short_label = self.alloc_label(short_label);
+                let end_label = self.alloc_label(end_label);
+                for e in [a, b].iter() {
+                    self.emit_expr(e)?;
+                    self.asm_out
+                        .push(asm::Stmt::Jmp(cond, Some(short_label.clone())));
+                    self.scopes.pop_local(1);
+                }
+                self.asm_out.push(asm::Stmt::PushBool(!short_value));
+                self.asm_out
+                    .push(asm::Stmt::Jmp(asm::Cond::Always, Some(end_label.clone())));
+                self.asm_out.push(asm::Stmt::Label(short_label));
+                self.asm_out.push(asm::Stmt::PushBool(short_value));
+                self.asm_out.push(asm::Stmt::Label(end_label));
+                self.scopes.push_local();
+            }
+            ExprKind::Op(a, op, b) => {
+                self.emit_expr(a)?;
+                self.emit_expr(b)?;
+                self.scopes.pop_local(2);
+                self.asm_out.push(self.binary_op(*op, expr));
+                self.scopes.push_local();
+            }
+            ExprKind::Unary(op, e) => {
+                self.emit_expr(e)?;
+                let stmt = match op {
+                    UnOp::Neg => asm::Stmt::Unary(asm::UnaryOp::Neg),
+                    UnOp::Not => asm::Stmt::Unary(asm::UnaryOp::Not),
+                    UnOp::Complement => asm::Stmt::Unary(asm::UnaryOp::Complement),
+                    UnOp::Len => asm::Stmt::Len,
+                };
+                self.asm_out.push(stmt);
+            }
+            ExprKind::Call(name, exprs)
+                if self.scopes.resolve(&name.node).is_some()
+                    || self.global(&name.node).is_some() =>
+            {
+                // call of a function value, the type checker made sure it takes `exprs.len()`
+                // arguments
+                self.asm_out.push(asm::Stmt::PushInline(0));
+                self.scopes.push_local();
+                for e in exprs {
+                    self.emit_expr(e)?;
+                }
+                match self.scopes.resolve(&name.node) {
+                    Some(slot) => self.asm_out.push(asm::Stmt::LoadLocal(slot)),
+                    None => {
+                        let slot = self.global(&name.node).unwrap();
+                        self.asm_out.push(asm::Stmt::LoadGlobal(slot))
+                    }
+                }
+                self.asm_out.push(asm::Stmt::CallIndirect);
+                self.asm_out.push(asm::Stmt::Pop(exprs.len() as i64));
+                self.scopes.pop_local(exprs.len());
+            }
+            ExprKind::Call(name, exprs) => {
+                match self.functions.get(&name.node) {
+                    None => return Err(self.unknown_binding(name.node, name.span)),
+                    Some(num_args) if *num_args != exprs.len() => {
+                        return Err(Error::Resolve(
+                            format!(
+                                "function `{}` expects {} arguments, found {}",
+                                self.env.get(name.node).unwrap(),
+                                num_args,
+                                exprs.len()
+                            ),
+                            expr.span,
+                        ))
+                    }
+                    _ => (),
+                }
+                self.asm_out.push(asm::Stmt::PushInline(0));
+                self.scopes.push_local();
+                for e in exprs {
+                    self.emit_expr(e)?;
+                }
+                let name: String = format!("func_{}", *self.env.get(name.node).unwrap());
+                self.asm_out.push(asm::Stmt::Call(name));
+                self.asm_out.push(asm::Stmt::Pop(exprs.len() as i64));
+                self.scopes.pop_local(exprs.len());
+                // self.scopes.push_local();
+            }
+            ExprKind::Error => {
+                return Err(Error::Parse(
+                    "found Expr::Error in emit_expr".into(),
+                    Some(expr.span),
+                ))
+            }
+        }
+        Ok(())
+    }
+    // `expr` is the operation itself, or the right hand side of a compound assignment; string
+    // operands turn `+` into a concatenation.
+    fn binary_op(&self, op: Opcode, expr: &Expr) -> asm::Stmt {
+        match op {
+            Opcode::Add if self.types.expr_type(expr) == Some(Type::Str) => asm::Stmt::Concat,
+            _ => asm::Stmt::Arith(arith_op(op)),
+        }
+    }
+    fn emit_loop_body(
+        &mut self,
+        body: &Stmt,
+        continue_label: String,
+        break_label: String,
+    ) -> Result<()> {
+        self.loops.push(Loop {
+            continue_label,
+            break_label,
+            stack_top: self.scopes.stack_top(),
+        });
+        let res = self.emit(body);
+        self.loops.pop();
+        res
+    }
+    // Assigns a slot to each global, a global bound more than once keeps its slot.
+    fn declare_globals(&mut self, stmts: &[&Stmt]) {
+        for s in stmts {
+            if let StmtKind::LetBinding(ident, _) = &s.node {
+                let slot = self.globals.len() as i64;
+                self.globals.entry(ident.node).or_insert(slot);
+            }
+        }
+    }
+    // Functions see all globals, the toplevel only the ones already bound (as in the type checker).
+    fn global(&self, ident: &Ident) -> Option<i64> {
+        if self.function_args.is_none() && !self.defined_globals.contains(ident) {
+            return None;
+        }
+        self.globals.get(ident).copied()
+    }
+
+    fn field_offset(&self, field: &SpannedIdent) -> Result<i64> {
+        match self.types.fields.get(&field.span) {
+            Some(offset) => Ok(*offset as i64),
+            None => Err(Error::Resolve(
+                format!("unknown field `{}`", self.env.get(field.node).unwrap()),
+                field.span,
+            )),
+        }
+    }
+
+    fn unknown_binding(&self, ident: Ident, span: Span) -> Error {
+        Error::Resolve(
+            format!("unknown identifier `{}`", self.env.get(ident).unwrap()),
+            span,
+        )
+    }
+    // Captured values are pushed by the call as the first locals of the function.
+    fn emit_function(
+        &mut self,
+        label: String,
+        args: &[SpannedIdent],
+        captures: &[Ident],
+        body: &Stmt,
+    ) -> Result<()> {
+        self.asm_out.push(asm::Stmt::Label(label));
+        let outer_scopes = std::mem::replace(&mut self.scopes, ScopeStack::new());
+        let outer_loops = std::mem::take(&mut self.loops);
+        let outer_args = self.function_args.replace(args.len());
+        for (i, a) in args.iter().enumerate() {
+            self.scopes
+                .add_argument(a.node, i as i64 - args.len() as i64);
+        }
+        for ident in captures {
+            self.scopes.push_local();
+            self.scopes.add_binding(*ident);
+        }
+        self.emit(body)?;
+        // falling off the end returns the result slot as initialized by the caller
+        self.asm_out.push(asm::Stmt::Ret);
+        self.scopes = outer_scopes;
+        self.loops = outer_loops;
+        self.function_args = outer_args;
+        Ok(())
+    }
+}
+
+fn arith_op(op: Opcode) -> asm::ArithOp {
+    match op {
+        Opcode::Add => asm::ArithOp::Add,
+        Opcode::Sub => asm::ArithOp::Sub,
+        Opcode::Mul => asm::ArithOp::Mul,
+        Opcode::Div => asm::ArithOp::Div,
+        Opcode::Mod => asm::ArithOp::Mod,
+        Opcode::BitAnd => asm::ArithOp::BitAnd,
+        Opcode::BitOr => asm::ArithOp::BitOr,
+        Opcode::BitXor => asm::ArithOp::BitXor,
+        Opcode::Shl => asm::ArithOp::Shl,
+        Opcode::Shr => asm::ArithOp::Shr,
+        Opcode::Ushr => asm::ArithOp::Ushr,
+        Opcode::Or => asm::ArithOp::Or,
+        Opcode::And => asm::ArithOp::And,
+        Opcode::Equal => asm::ArithOp::Equal,
+        Opcode::NotEqual => asm::ArithOp::NotEqual,
+        Opcode::LessThan => asm::ArithOp::LessThan,
+        Opcode::LessEqual => asm::ArithOp::LessEqual,
+        Opcode::GreaterThan => asm::ArithOp::GreaterThan,
+        Opcode::GreaterEqual => asm::ArithOp::GreaterEqual,
+    }
+}
+
+/// Translates lang1 programs into xas assembly (and, via [`asm::assemble`], into bytecode).
+///
+/// Function declarations are emitted first, each under a `func_<name>` label, followed by the
+/// toplevel statements starting at the `entry` label. Bindings of the toplevel scope are stored in
+/// the VM's globals segment, so functions can access them.
+pub struct Compiler {}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Compiler {}
+    }
+
+    /// Type check and compile an already parsed program. `env` must be the identifier map the
+    /// program was parsed with. Programs with imports have to be linked by [`module::Modules`]
+    /// first.
+    pub fn compile(
+        &self,
+        env: &HandleMap<&str>,
+        program: &[Toplevel],
+    ) -> Result<Vec<asm::Section>> {
+        module::reject_imports(program)?;
+        let types = typeck::check(env, program).map_err(|mut errors| errors.remove(0))?;
+        let mut stmts = Vec::new();
+        let mut decls = Vec::new();
+        for p in program {
+            match p {
+                Toplevel::Stmt(s) => stmts.push(s),
+                Toplevel::Declaration(d) => decls.push(d),
+            }
+        }
+
+        let mut codegen = CodeGen::new(env);
+        codegen.types = types;
+        codegen.declare_globals(&stmts);
+        for d in &decls {
+            match d {
+                Declaration::Function(name, args, _) => {
+                    codegen.functions.insert(name.node, args.len());
+                }
+                Declaration::Struct(name, fields) => {
+                    let fields = fields.iter().map(|field| field.node).collect();
+                    codegen.structs.insert(name.node, fields);
+                }
+                Declaration::Import(_) => (),
+            }
+        }
+
+        if !codegen.functions.is_empty() {
+            codegen
+                .asm_out
+                .push(asm::Stmt::Jmp(asm::Cond::Always, Some("entry".into())));
+        }
+
+        for d in &decls {
+            match d {
+                Declaration::Function(name, args, body) => {
+                    let label = format!("func_{}", env.get(name.node).unwrap());
+                    codegen.emit_function(label, args, &[], body)?
+                }
+                Declaration::Struct(..) | Declaration::Import(_) => (),
+            }
+        }
+        codegen.asm_out.push(asm::Stmt::Label("entry".into()));
+        for s in &stmts {
+            codegen.emit(s)?;
+        }
+        Ok(vec![
+            asm::Section::Data(Vec::new()),
+            asm::Section::Code(codegen.asm_out),
+        ])
+    }
+
+    /// Parse lang1 source code. On failure all syntax errors (including the ones the parser
+    /// recovered from) are returned.
+    pub fn parse<'input>(
+        &self,
+        env: &mut HandleMap<&'input str>,
+        code: &'input str,
+    ) -> std::result::Result<Vec<Toplevel>, Vec<Error>> {
+        let mut errors = Vec::new();
+        let res = lang1::ProgramParser::new().parse(env, &mut errors, code);
+        let mut errors: Vec<Error> = errors.into_iter().map(|e| e.error.into()).collect();
+        match res {
+            Ok(program) if errors.is_empty() => Ok(program),
+            Ok(_) => Err(errors),
+            Err(err) => {
+                errors.push(err.into());
+                Err(errors)
+            }
+        }
+    }
+
+    /// Parse and compile lang1 source code.
+    pub fn compile_source(&self, code: &str) -> Result<Vec<asm::Section>> {
+        let mut env = HandleMap::new();
+        let program = self
+            .parse(&mut env, code)
+            .map_err(|mut errors| errors.remove(0))?;
+        self.compile(&env, &program)
+    }
+
+    /// Parse, compile and assemble lang1 source code into a program that can be run by [`crate::bytecode::Vm`].
+    pub fn build(&self, code: &str) -> Result<Program> {
+        asm::assemble(&self.compile_source(code)?)
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CodeGen, Compiler, Toplevel};
+    use crate::asm::{ArithOp, Cond, Stmt};
+    use crate::ast::Span;
+    use crate::bytecode::{IoChannels, Vm};
+    use crate::error::{Error, Result, Trap};
+    use crate::lang1;
+    use handy::HandleMap;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_basic() {
+        let code = include_str!("test_compiler_basic.l1");
+        let mut env = HandleMap::new();
+        let mut errors = Vec::new();
+        let program = lang1::ProgramParser::new()
+            .parse(&mut env, &mut errors, code)
+            .unwrap();
+
+        let mut codegen = CodeGen::new(&env);
+        let stmts: Vec<_> = program
+            .iter()
+            .filter_map(|p| match p {
+                Toplevel::Stmt(s) => Some(s),
+                _ => None,
+            })
+            .collect();
+        codegen.declare_globals(&stmts);
+
+        for p in &program {
+            match p {
+                Toplevel::Stmt(s) => codegen.emit(s).unwrap(),
+                _ => (),
+                // Toplevel::Declaration(d) => decls.push(d),
+            }
+        }
+        println!("asm: {:?}", codegen.asm_out);
+        let asm_ref = [
+            // Stmt::Jmp(Cond::Always, Some("entry".into())),
+            // Stmt::Label("entry".into()),
+            Stmt::PushInline(123),
+            Stmt::StoreGlobal(0),
+            Stmt::PushInline(321),
+            Stmt::PushInline(432),
+            Stmt::LoadLocal(0),
+            Stmt::Output(0),
+            Stmt::Pop(2),
+            Stmt::LoadGlobal(0),
+            Stmt::Output(0),
+            Stmt::Pop(0),
+            Stmt::LoadGlobal(0),
+            Stmt::Output(0),
+            Stmt::Label("while0".into()),
+            Stmt::LoadGlobal(0),
+            Stmt::PushInline(0),
+            Stmt::Arith(ArithOp::NotEqual),
+            Stmt::Jmp(Cond::Zero, Some("while_end0".into())),
+            Stmt::PushInline(1),
+            Stmt::LoadGlobal(0),
+            Stmt::PushInline(1),
+            Stmt::Arith(ArithOp::Sub),
+            Stmt::StoreGlobal(0),
+            Stmt::Pop(1),
+            Stmt::Jmp(Cond::Always, Some("while0".into())),
+            Stmt::Label("while_end0".into()),
+        ];
+        assert_eq!(codegen.asm_out[..], asm_ref);
+    }
+
+    fn run(code: &str) -> Vec<String> {
+        let prog = Compiler::new().build(code).unwrap();
+        let (send, recv) = channel();
+        let mut io = IoChannels::new();
+        io.channels.push(send);
+        let mut vm = Vm::from_program(prog);
+        vm.exec(Some(&io)).unwrap();
+        recv.try_iter().collect()
+    }
+
+    #[test]
+    fn build_and_run() {
+        assert_eq!(
+            run(include_str!("../data/test_factorial.l1")),
+            [10, 3628800].map(|v| v.to_string())
+        );
+    }
+
+    #[test]
+    fn early_return() {
+        let code = "
+            fn sign(a) {
+                let x = 1;
+                if a < 0 + 0 {
+                    let y = 2;
+                    return 0 - 1;
+                }
+                if a == 0 {
+                    return 0;
+                }
+                return x;
+            }
+            fn nothing() {
+                let z = 3;
+            }
+            print sign(0 - 5), sign(0), sign(7), nothing();
+        ";
+        assert_eq!(run(code), [-1, 0, 1, 0].map(|v| v.to_string()));
+    }
+
+    #[test]
+    fn short_circuit() {
+        let code = "
+            let n = 0;
+            print n != 0 and 10 / n > 1, n == 0 or 10 / n > 1, true and n < 1, false or false;
+        ";
+        assert_eq!(run(code), [false, true, true, false].map(|v| v.to_string()));
+    }
+
+    #[test]
+    fn strings() {
+        let code = r#"
+            let s = "ab";
+            s += "c" + "";
+            print s, len(s), s == "abc", s + "\n" != s;
+        "#;
+        assert_eq!(run(code), ["abc", "3", "true", "true"]);
+    }
+
+    #[test]
+    fn arrays() {
+        let code = r#"
+            let a = [1, 2, 3];
+            let names = [["x"], []];
+            a[0] = a[1] + a[2];
+            a[2] *= 10;
+            names[1] = ["y", "z"];
+            names[1][0] += "!";
+            print a, len(a), names, len(names[1]), a == a, [1] == [1];
+        "#;
+        assert_eq!(
+            run(code),
+            ["[5, 2, 30]", "3", "[[x], [y!, z]]", "2", "true", "false"]
+        );
+    }
+
+    #[test]
+    fn closures() {
+        let code = r#"
+            fn twice(f, x) { return f(f(x)); }
+            fn inc(x) { return x + 1; }
+            fn adder(n) { return fn(x) { return x + n; }; }
+            let base = 10;
+            let add_base = fn(x) { base += x; return base; };
+            base = 0;
+            let add5 = adder(5);
+            let counter = fn() {
+                let count = fn(a) { return len(a) + base; };
+                return count([1, 2]);
+            };
+            let g = inc;
+            print add_base(1), add_base(2), base, twice(add5, 1), twice(inc, 1), g(41), counter();
+            let shout = fn(s) { return s + "!"; };
+            print add_base == add_base, g == inc, shout(shout("hi"));
+        "#;
+        assert_eq!(
+            run(code),
+            ["1", "3", "3", "11", "3", "42", "5", "true", "false", "hi!!"]
+        );
+    }
+
+    #[test]
+    fn globals() {
+        let code = r#"
+            fn bump(n) { counter += n; return counter; }
+            fn describe() { return name + "!"; }
+            let counter = 0;
+            let name = "c";
+            bump(2);
+            bump(3);
+            {
+                let counter = 100;
+                print bump(1), counter;
+            }
+            print counter, describe();
+            let counter = 10;
+            print bump(1);
+        "#;
+        assert_eq!(run(code), ["6", "100", "6", "c!", "11"]);
+
+        let code = "fn f() { return g; } print f(); let g = 1;";
+        let (out, res, _) = run_with_heap_limit(code, 10);
+        assert!(out.is_empty());
+        assert_eq!(res, Err(Trap::InvalidGlobal(0).into()));
+    }
+
+    #[test]
+    fn structs() {
+        let code = r#"
+            struct Point { x, y }
+            struct Line { from, to, name }
+            fn length(l) { return l.to.x - l.from.x + l.to.y - l.from.y; }
+            let p = Point { y: 2, x: 1 };
+            let l = Line { name: "l", from: p, to: Point { x: 4, y: 6 } };
+            p.x += 1;
+            l.name = l.name + "!";
+            l.to.y *= 2;
+            if (Point { x: 0, y: 0 }).x == 0 and l.to.x > p.x {
+                print p, l, length(l), l.from == p, p == Point { x: 2, y: 2 };
+            }
+        "#;
+        assert_eq!(
+            run(code),
+            ["{2, 2}", "{{2, 2}, {4, 12}, l!}", "12", "true", "false"]
+        );
+    }
+
+    // Run with a small heap limit, so that the program only finishes if garbage is collected.
+    fn run_with_heap_limit(code: &str, limit: usize) -> (Vec<String>, Result<()>, Vm) {
+        let prog = Compiler::new().build(code).unwrap();
+        let (send, recv) = channel();
+        let mut io = IoChannels::new();
+        io.channels.push(send);
+        let mut vm = Vm::from_program(prog);
+        vm.heap.limit = Some(limit);
+        let res = vm.exec(Some(&io));
+        (recv.try_iter().collect(), res, vm)
+    }
+
+    #[test]
+    fn gc_stress() {
+        let code = r#"
+            fn make(n) {
+                let rows = [[], []];
+                for i in 0..n {
+                    rows = [[i, len("x" + "y")], rows[0]];
+                }
+                return rows;
+            }
+            let keep = make(10);
+            let s = "";
+            for i in 0..2000 {
+                let tmp = make(5);
+                s += "ab";
+                if len(s) > 7 {
+                    s = "";
+                }
+            }
+            print keep, len(s), s;
+        "#;
+        let (out, res, vm) = run_with_heap_limit(code, 100);
+        res.unwrap();
+        assert_eq!(out, ["[[9, 2], [8, 2]]", "0", ""]);
+        assert!(vm.heap.stats.collections > 0);
+        assert!(vm.heap.stats.max_live <= 100);
+        assert_eq!(
+            vm.heap.stats.allocated - vm.heap.stats.freed,
+            vm.heap.live()
+        );
+    }
+
+    #[test]
+    fn gc_out_of_memory() {
+        let code = "
+            fn deep(n) {
+                let a = [n];
+                if n == 0 {
+                    return 0;
+                }
+                return deep(n - 1) + a[0];
+            }
+            print deep(1000);
+        ";
+        let (_, res, _) = run_with_heap_limit(code, 100);
+        assert_eq!(res, Err(Trap::OutOfMemory { limit: 100 }.into()));
+    }
+
+    #[test]
+    fn loop_control_errors() {
+        for (code, msg) in [
+            ("break;", "resolve error: break outside of loop"),
+            (
+                "while true { } continue;",
+                "resolve error: continue outside of loop",
+            ),
+            (
+                "fn f() { break; } while true { print f(); }",
+                "resolve error: break outside of loop",
+            ),
+        ]
+        .iter()
+        {
+            let err = Compiler::new().compile_source(code).err().unwrap();
+            assert_eq!(err.to_string(), *msg);
+        }
+    }
+
+    #[test]
+    fn type_errors() {
+        for (code, msg) in [
+            (
+                "let x = 1; if x { print x; }",
+                "type error: condition must be `bool`, found `int`",
+            ),
+            (
+                "while 1 + 1 { }",
+                "type error: condition must be `bool`, found `int`",
+            ),
+            (
+                "print not 1;",
+                "type error: operand of `not` must be `bool`, found `int`",
+            ),
+            (
+                "print true and 0;",
+                "type error: operand of `and` must be `bool`, found `int`",
+            ),
+            (
+                "let b = true; b = 1;",
+                "type error: cannot assign `int` to `b` of type `bool`",
+            ),
+        ]
+        .iter()
+        {
+            let err = Compiler::new().compile_source(code).err().unwrap();
+            assert_eq!(err.to_string(), *msg);
+        }
+    }
+
+    #[test]
+    fn imports() {
+        // only the module loader resolves imports, reported before the unknown names they bring in
+        let code = "import \"util.l1\";\nprint util::f();\n";
+        let err = Compiler::new().compile_source(code).err().unwrap();
+        assert_eq!(
+            err,
+            Error::Module(
+                "cannot import `util.l1` into a single source, imports are resolved by the module loader (`module::Modules`)".into(),
+                Some(Span::new(7, 16))
+            )
+        );
+        assert_eq!(Compiler::new().build(code).err(), Some(err));
+    }
+}
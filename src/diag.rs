@@ -0,0 +1,145 @@
+use crate::{ast::Span, error::Error};
+
+/// A named piece of source code that errors can be reported against.
+pub struct SourceFile<'a> {
+    pub name: &'a str,
+    pub source: &'a str,
+}
+
+impl<'a> SourceFile<'a> {
+    pub fn new(name: &'a str, source: &'a str) -> Self {
+        SourceFile { name, source }
+    }
+
+    /// 1-based line and column of a byte offset. Offsets inside a multi-byte character (the end of
+    /// an invalid token is one byte past its start) count as the end of that character.
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let mut offset = offset.min(self.source.len());
+        while !self.source.is_char_boundary(offset) {
+            offset += 1;
+        }
+        let before = &self.source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let col = before[line_start..].chars().count() + 1;
+        (line, col)
+    }
+
+    fn line_text(&self, line: usize) -> &'a str {
+        self.source.lines().nth(line - 1).unwrap_or("")
+    }
+
+    /// Render a message and the file:line:col it refers to, followed by the offending source line
+    /// with the span underlined. Spans reaching over several lines are underlined up to the end of
+    /// their first line.
+    pub fn render_span(&self, message: &str, span: Span) -> String {
+        let (line, col) = self.location(span.start);
+        let text = self.line_text(line);
+        let (end_line, end_col) = self.location(span.end);
+        let width = if end_line == line && end_col > col {
+            end_col - col
+        } else if end_line > line {
+            (text.chars().count() + 1).saturating_sub(col).max(1)
+        } else {
+            1
+        };
+        let gutter = " ".repeat(line.to_string().len());
+        format!(
+            "{}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+            message,
+            gutter,
+            self.name,
+            line,
+            col,
+            gutter,
+            line,
+            text,
+            gutter,
+            " ".repeat(col - 1),
+            "^".repeat(width)
+        )
+    }
+
+    /// Render an error, with source excerpt if it carries a location.
+    pub fn render(&self, err: &Error) -> String {
+        match err.span() {
+            Some(span) => self.render_span(&err.to_string(), span),
+            None => format!("{}\n", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SourceFile;
+    use crate::ast::Span;
+    use crate::compile::Compiler;
+
+    #[test]
+    fn location() {
+        let file = SourceFile::new("test.l1", "let a = 1;\nprint b;\n");
+        assert_eq!(file.location(0), (1, 1));
+        assert_eq!(file.location(4), (1, 5));
+        assert_eq!(file.location(11), (2, 1));
+        assert_eq!(file.location(17), (2, 7));
+
+        let file = SourceFile::new("test.l1", "print \"é\", é;");
+        assert_eq!(file.location(8), (1, 9));
+        assert_eq!(file.location(10), (1, 10));
+    }
+
+    #[test]
+    fn render() {
+        let file = SourceFile::new("test.l1", "let a = 1;\nprint b;\n");
+        assert_eq!(
+            file.render_span("error: unknown identifier `b`", Span::new(17, 18)),
+            "error: unknown identifier `b`\n --> test.l1:2:7\n  |\n2 | print b;\n  |       ^\n"
+        );
+    }
+
+    #[test]
+    fn render_compile_errors() {
+        let code = "fn f(a, b) {\n    return a + b;\n}\nprint f(1);\n";
+        let err = Compiler::new().compile_source(code).err().unwrap();
+        assert_eq!(
+            SourceFile::new("arity.l1", code).render(&err),
+            "resolve error: function `f` expects 2 arguments, found 1\n --> arity.l1:4:7\n  |\n4 | print f(1);\n  |       ^^^^\n"
+        );
+
+        let code = "let a = 1;\nprint a * + 2;\n";
+        let err = Compiler::new().compile_source(code).err().unwrap();
+        let rendered = SourceFile::new("syntax.l1", code).render(&err);
+        assert!(rendered.starts_with("parse error: unexpected token `+`"));
+        assert!(
+            rendered.ends_with(" --> syntax.l1:2:11\n  |\n2 | print a * + 2;\n  |           ^\n")
+        );
+
+        let code = "print \"é\";\nprint é;\n";
+        let err = Compiler::new().compile_source(code).err().unwrap();
+        assert_eq!(
+            SourceFile::new("utf8.l1", code).render(&err),
+            "parse error: invalid token\n --> utf8.l1:2:7\n  |\n2 | print é;\n  |       ^\n"
+        );
+
+        let code = "print 99999999999999999999;";
+        let err = Compiler::new().compile_source(code).err().unwrap();
+        assert_eq!(
+            SourceFile::new("literal.l1", code).render(&err),
+            "parse error: integer literal out of range\n --> literal.l1:1:7\n  |\n1 | print 99999999999999999999;\n  |       ^^^^^^^^^^^^^^^^^^^^\n"
+        );
+
+        let code = "let s = 1;\nprint 0x10000000000000000;";
+        let err = Compiler::new().compile_source(code).err().unwrap();
+        assert_eq!(
+            SourceFile::new("literal.l1", code).render(&err),
+            "parse error: integer literal out of range\n --> literal.l1:2:7\n  |\n2 | print 0x10000000000000000;\n  |       ^^^^^^^^^^^^^^^^^^^\n"
+        );
+
+        let code = "print \"a\\qb\";";
+        let err = Compiler::new().compile_source(code).err().unwrap();
+        assert_eq!(
+            SourceFile::new("escape.l1", code).render(&err),
+            "parse error: invalid escape sequence in string literal\n --> escape.l1:1:7\n  |\n1 | print \"a\\qb\";\n  |       ^^^^^^\n"
+        );
+    }
+}
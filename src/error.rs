@@ -0,0 +1,189 @@
+use crate::ast::{Span, Spanned};
+use lalrpop_util::ParseError;
+use std::fmt::{Display, Formatter};
+
+/// Runtime faults raised by the [`crate::bytecode::Vm`] and the [`crate::eval::Evaluator`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    StackUnderflow {
+        offs: i64,
+        len: usize,
+    },
+    InvalidJump {
+        target: i64,
+        len: usize,
+    },
+    InvalidConst(i64),
+    InvalidLocal(i64),
+    InvalidGlobal(i64),
+    InvalidReference(usize),
+    InvalidChannel(u16),
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    DivisionByZero,
+    ReturnWithoutCall,
+    OutOfMemory {
+        limit: usize,
+    },
+    /// Raised by the evaluator, which runs each call on the native stack.
+    CallDepthExceeded {
+        limit: usize,
+    },
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// Syntax errors from the lang1 or xas parsers.
+    Parse(String, Option<Span>),
+    /// Names that cannot be resolved by the compiler or the evaluator, and calls with the wrong number of arguments.
+    Resolve(String, Span),
+    /// Expressions used with a type they cannot have, e.g. an integer as a condition.
+    Type(String, Span),
+    /// Code the assembler cannot translate into bytecode (e.g. unknown labels or operands out of range).
+    Assemble(String),
+    /// Imports that cannot be loaded, and import cycles.
+    Module(String, Option<Span>),
+    /// Program files that cannot be decoded, e.g. of an unsupported format version.
+    Format(String),
+    Runtime(Trap),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Trap {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Trap::StackUnderflow { offs, len } => {
+                write!(fmt, "stack underflow: {} (of {})", offs, len)
+            }
+            Trap::InvalidJump { target, len } => {
+                write!(fmt, "jmp to invalid code location {} (of {})", target, len)
+            }
+            Trap::InvalidConst(offs) => write!(fmt, "invalid constant: {}", offs),
+            Trap::InvalidLocal(slot) => write!(fmt, "invalid local slot: {}", slot),
+            Trap::InvalidGlobal(slot) => write!(fmt, "invalid global slot: {}", slot),
+            Trap::InvalidReference(r) => write!(fmt, "invalid heap reference: {}", r),
+            Trap::InvalidChannel(channel) => write!(fmt, "invalid output channel: #{}", channel),
+            Trap::IndexOutOfBounds { index, len } => {
+                write!(fmt, "index out of bounds: {} (of {})", index, len)
+            }
+            Trap::DivisionByZero => write!(fmt, "division by zero"),
+            Trap::ReturnWithoutCall => write!(fmt, "ret with empty call stack"),
+            Trap::OutOfMemory { limit } => {
+                write!(fmt, "out of memory: more than {} live objects", limit)
+            }
+            Trap::CallDepthExceeded { limit } => {
+                write!(fmt, "call depth exceeded: more than {} nested calls", limit)
+            }
+            Trap::TypeMismatch { expected, found } => {
+                write!(fmt, "type mismatch: expected {}, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Error::Parse(msg, _) => write!(fmt, "parse error: {}", msg),
+            Error::Resolve(msg, _) => write!(fmt, "resolve error: {}", msg),
+            Error::Type(msg, _) => write!(fmt, "type error: {}", msg),
+            Error::Assemble(msg) => write!(fmt, "assembler error: {}", msg),
+            Error::Module(msg, _) => write!(fmt, "module error: {}", msg),
+            Error::Format(msg) => write!(fmt, "invalid program file: {}", msg),
+            Error::Runtime(trap) => write!(fmt, "runtime error: {}", trap),
+        }
+    }
+}
+
+impl Error {
+    /// The location of the error in the source code, if known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Parse(_, span) | Error::Module(_, span) => *span,
+            Error::Resolve(_, span) | Error::Type(_, span) => Some(*span),
+            Error::Assemble(_) | Error::Format(_) | Error::Runtime(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Trap> for Error {
+    fn from(trap: Trap) -> Self {
+        Error::Runtime(trap)
+    }
+}
+
+impl<T: Display, E: UserError> From<ParseError<usize, T, E>> for Error {
+    fn from(err: ParseError<usize, T, E>) -> Self {
+        let span = match &err {
+            ParseError::InvalidToken { location } => Some(Span::new(*location, *location + 1)),
+            ParseError::UnrecognizedEOF { location, .. } => Some(Span::new(*location, *location)),
+            ParseError::UnrecognizedToken {
+                token: (start, _, end),
+                ..
+            }
+            | ParseError::ExtraToken {
+                token: (start, _, end),
+            } => Some(Span::new(*start, *end)),
+            ParseError::User { error } => error.span(),
+        };
+        let msg = match &err {
+            ParseError::InvalidToken { .. } => "invalid token".to_string(),
+            ParseError::UnrecognizedEOF { expected, .. } => {
+                format!("unexpected end of file{}", expected_list(expected))
+            }
+            ParseError::UnrecognizedToken {
+                token: (_, token, _),
+                expected,
+            } => format!("unexpected token `{}`{}", token, expected_list(expected)),
+            ParseError::ExtraToken {
+                token: (_, token, _),
+            } => {
+                format!("extra token `{}`", token)
+            }
+            ParseError::User { error } => error.message(),
+        };
+        Error::Parse(msg, span)
+    }
+}
+
+/// Errors raised by the actions of a grammar, which know where they occurred if the grammar
+/// captured the location of the offending token.
+pub trait UserError {
+    fn message(&self) -> String;
+    fn span(&self) -> Option<Span>;
+}
+
+impl UserError for &str {
+    fn message(&self) -> String {
+        self.to_string()
+    }
+    fn span(&self) -> Option<Span> {
+        None
+    }
+}
+
+impl UserError for Spanned<&str> {
+    fn message(&self) -> String {
+        self.node.to_string()
+    }
+    fn span(&self) -> Option<Span> {
+        Some(self.span)
+    }
+}
+
+fn expected_list(expected: &[String]) -> String {
+    if expected.is_empty() {
+        String::new()
+    } else {
+        format!(", expected one of {}", expected.join(", "))
+    }
+}
@@ -1,94 +1,790 @@
-use crate::ast::{Expr, Opcode, Stmt};
+use crate::ast::{
+    Declaration, Expr, ExprKind, Ident, Opcode, Span, SpannedIdent, Stmt, StmtKind, Toplevel, UnOp,
+};
+use crate::error::{Error, Result, Trap};
+use crate::module;
 use handy::Handle;
+use log::debug;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::rc::Rc;
+use std::sync::mpsc::Sender;
 
+/// Values of the evaluator. Unlike the VM's values, strings, arrays, records and functions are
+/// reference counted instead of living on a heap.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Str(Rc<str>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Record(Rc<Record>),
+    Function(Rc<Closure>),
+}
+
+/// Instance of a struct. The fields are stored in order of declaration.
+#[derive(Debug)]
+pub struct Record {
+    pub name: Ident,
+    pub fields: RefCell<Vec<Value>>,
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Bool(_) => "bool",
+            Value::Str(_) => "string",
+            Value::Array(_) => "array",
+            Value::Record(_) => "record",
+            Value::Function(_) => "function",
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Value::Int(v) => write!(fmt, "{}", v),
+            Value::Bool(v) => write!(fmt, "{}", v),
+            Value::Str(s) => write!(fmt, "{}", s),
+            Value::Array(elements) => {
+                let elements: Vec<_> = elements.borrow().iter().map(|v| v.to_string()).collect();
+                write!(fmt, "[{}]", elements.join(", "))
+            }
+            Value::Record(record) => {
+                let fields: Vec<_> = record
+                    .fields
+                    .borrow()
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect();
+                write!(fmt, "{{{}}}", fields.join(", "))
+            }
+            Value::Function(_) => write!(fmt, "<function>"),
+        }
+    }
+}
+
+// arrays, records and functions are equal if they are the same object, as in the VM
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => Rc::ptr_eq(a, b),
+            (Value::Record(a), Value::Record(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+struct Function {
+    args: Vec<Ident>,
+    body: Stmt,
+}
+
+/// Function value, with copies of the bindings visible where it was created. Declared functions
+/// used as values capture nothing.
+pub struct Closure {
+    function: Rc<Function>,
+    captures: HashMap<Handle, Value>,
+}
+
+impl Debug for Closure {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        write!(fmt, "Closure({:?})", self.function.args)
+    }
+}
+
+enum Flow {
+    Normal,
+    Return(Value),
+    Break,
+    Continue,
+}
+
+impl Flow {
+    // `break` and `continue` may not leave a function body or the toplevel
+    fn check_loop(self, span: Span) -> Result<Option<Value>> {
+        match self {
+            Flow::Normal => Ok(None),
+            Flow::Return(v) => Ok(Some(v)),
+            Flow::Break => Err(Error::Resolve("break outside of loop".into(), span)),
+            Flow::Continue => Err(Error::Resolve("continue outside of loop".into(), span)),
+        }
+    }
+}
+
+/// Default for [`Evaluator::max_depth`], which fits into the 8 MiB stack of the main thread even
+/// in debug builds.
+pub const MAX_DEPTH: usize = 1000;
+
+/// Tree-walking interpreter for lang1, serving as the reference semantics for the compiler and VM.
+///
+/// Scoping follows the compiler's `ScopeStack`: every block opens a new scope, `let` shadows
+/// bindings of the same name and declared functions only see their own arguments and locals, and
+/// the globals. Anonymous functions additionally see copies of the local bindings of the scopes
+/// they were created in. Globals are the bindings made directly at the toplevel.
 pub struct Evaluator {
     // ident_env: &'input mut dyn HandleMapDedup<&'input str>,
-    env: HashMap<Handle, i64>,
+    functions: HashMap<Handle, Rc<Function>>,
+    // declared fields of each struct
+    structs: HashMap<Handle, Vec<Ident>>,
+    // one list of block scopes per active function call (the first one belongs to the toplevel,
+    // it is empty outside of blocks)
+    frames: Vec<Vec<HashMap<Handle, Value>>>,
+    globals: HashMap<Handle, Value>,
+    // the compiler's slot of each global, to report reads before its binding like the VM
+    global_slots: HashMap<Handle, i64>,
+    /// Receives the values of `print` statements, rendered as text. Without it they are printed
+    /// to stdout.
+    pub output: Option<Sender<String>>,
+    /// Calls nest on the native stack, deeper calls trap instead of overflowing it.
+    pub max_depth: usize,
 }
 
 impl Evaluator {
     pub fn new() -> Self {
         Evaluator {
             // ident_env: &mut env,
-            env: HashMap::new(),
+            functions: HashMap::new(),
+            structs: HashMap::new(),
+            frames: vec![Vec::new()],
+            globals: HashMap::new(),
+            global_slots: HashMap::new(),
+            output: None,
+            max_depth: MAX_DEPTH,
+        }
+    }
+
+    /// Run a whole program. As in the compiled code, functions can be called before their declaration.
+    pub fn run(&mut self, program: &[Toplevel]) -> Result<()> {
+        for toplevel in program {
+            if let Toplevel::Declaration(decl) = toplevel {
+                self.declare(decl)?;
+            }
+        }
+        for toplevel in program {
+            if let Toplevel::Stmt(stmt) = toplevel {
+                if let StmtKind::LetBinding(ident, _) = &stmt.node {
+                    self.declare_global(ident.node);
+                }
+            }
+        }
+        for toplevel in program {
+            if let Toplevel::Stmt(stmt) = toplevel {
+                self.execute(stmt)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Imports are rejected, they have to be linked by [`module::Modules`] first.
+    pub fn declare(&mut self, decl: &Declaration) -> Result<()> {
+        match decl {
+            Declaration::Function(name, args, body) => {
+                let function = Function {
+                    args: args.iter().map(|a| a.node).collect(),
+                    body: (**body).clone(),
+                };
+                self.functions.insert(name.node, Rc::new(function));
+            }
+            Declaration::Struct(name, fields) => {
+                let fields = fields.iter().map(|field| field.node).collect();
+                self.structs.insert(name.node, fields);
+            }
+            Declaration::Import(file) => return Err(module::import_error(file)),
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, stmt: &Stmt) -> Result<()> {
+        match self.exec(stmt)?.check_loop(stmt.span)? {
+            None => Ok(()),
+            Some(_) => Err(Error::Resolve(
+                "return outside of function".into(),
+                stmt.span,
+            )),
+        }
+    }
+
+    /// Evaluate an expression in the toplevel scope, e.g. to show its value in the REPL.
+    pub fn evaluate(&mut self, expr: &Expr) -> Result<Value> {
+        self.eval(expr)
+    }
+
+    // Slots are numbered in the order of the toplevel bindings, as in `CodeGen::declare_globals`.
+    fn declare_global(&mut self, ident: Ident) {
+        let slot = self.global_slots.len() as i64;
+        self.global_slots.entry(ident).or_insert(slot);
+    }
+
+    // A name that is neither bound nor a function. Globals can be used by functions called
+    // before the toplevel binds them, which traps in the VM.
+    fn unbound(&self, ident: Ident, span: Span) -> Error {
+        match self.global_slots.get(&ident) {
+            Some(&slot) => Trap::InvalidGlobal(slot).into(),
+            None => unresolved("variable", span),
         }
     }
 
-    pub fn execute(&mut self, stmt: Stmt) {
-        match stmt {
-            Stmt::LetBinding(ident, expr) => {
-                let v = self.eval(expr);
+    fn scopes(&mut self) -> &mut Vec<HashMap<Handle, Value>> {
+        self.frames.last_mut().unwrap()
+    }
+
+    // local bindings shadow globals
+    fn lookup(&mut self, ident: Ident) -> Option<&mut Value> {
+        let scopes = self.frames.last_mut().unwrap();
+        scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(&ident))
+            .or(self.globals.get_mut(&ident))
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Result<Flow> {
+        match &stmt.node {
+            StmtKind::LetBinding(ident, expr) => {
+                let v = self.eval(expr)?;
                 // let h = self.ide
-                self.env.insert(ident, v);
+                match self.scopes().last_mut() {
+                    Some(scope) => scope.insert(ident.node, v),
+                    None => {
+                        self.declare_global(ident.node);
+                        self.globals.insert(ident.node, v)
+                    }
+                };
             }
-            Stmt::Print(exprs) => {
-                for e in exprs {
-                    println!("Print: {}", self.eval(e));
+            StmtKind::Assign(ident, expr, op) => {
+                // like the compiled code, load the old value before evaluating the right-hand side
+                let v = match op {
+                    Some(op) => {
+                        let old = match self.lookup(ident.node) {
+                            Some(old) => old.clone(),
+                            None => return Err(self.unbound(ident.node, ident.span)),
+                        };
+                        binop(*op, old, self.eval(expr)?)?
+                    }
+                    None => self.eval(expr)?,
+                };
+                if let Some(old) = self.lookup(ident.node) {
+                    *old = v;
+                } else if self.global_slots.contains_key(&ident.node) {
+                    // the VM stores into the global's slot even if it was not bound yet
+                    self.globals.insert(ident.node, v);
+                } else {
+                    return Err(unresolved("variable", ident.span));
                 }
             }
-            Stmt::Block(stmts, _) => {
-                for s in stmts {
-                    self.execute(s);
+            StmtKind::AssignIndex(array, index, expr, op) => {
+                let (elements, index) = self.eval_index(array, index)?;
+                match op {
+                    Some(op) => {
+                        let index = check_index(&elements, index)?;
+                        let element = elements.borrow()[index].clone();
+                        let v = self.eval(expr)?;
+                        elements.borrow_mut()[index] = binop(*op, element, v)?;
+                    }
+                    None => {
+                        let v = self.eval(expr)?;
+                        let index = check_index(&elements, index)?;
+                        elements.borrow_mut()[index] = v;
+                    }
                 }
             }
-            Stmt::IfElse(e, if_stmt, else_stmt) => {
-                let v = self.eval(e);
-                println!("ifelse: {}", v);
-                if v != 0 {
-                    self.execute(*if_stmt);
-                } else {
-                    if let Some(else_stmt) = else_stmt {
-                        self.execute(*else_stmt);
+            StmtKind::AssignField(record, field, expr, op) => {
+                let record = self.eval_record(record)?;
+                let offset = self.field_offset(&record, field)?;
+                let v = match op {
+                    Some(op) => {
+                        let old = record.fields.borrow()[offset].clone();
+                        binop(*op, old, self.eval(expr)?)?
+                    }
+                    None => self.eval(expr)?,
+                };
+                record.fields.borrow_mut()[offset] = v;
+            }
+            StmtKind::Print(exprs) => {
+                for e in exprs {
+                    let v = self.eval(e)?;
+                    match &self.output {
+                        Some(output) => output
+                            .send(v.to_string())
+                            .map_err(|_| Trap::InvalidChannel(0))?,
+                        None => println!("Print: {}", v),
                     }
                 }
-            } // Stmt::Expr(e) => {
+            }
+            StmtKind::Block(stmts) => {
+                self.scopes().push(HashMap::new());
+                let flow = self.exec_block(stmts);
+                self.scopes().pop();
+                return flow;
+            }
+            StmtKind::IfElse(e, if_stmt, else_stmt) => {
+                let v = self.eval_bool(e)?;
+                debug!("ifelse: {}", v);
+                if v {
+                    return self.exec(if_stmt);
+                } else if let Some(else_stmt) = else_stmt {
+                    return self.exec(else_stmt);
+                }
+            } // StmtKind::Expr(e) => {
             //     self.eval(e);
             // }
-            Stmt::While(e, body) => loop {
-                loop {
-                    let v = self.eval(e.clone());
-                    if v == 0 {
-                        break;
+            StmtKind::While(e, body) => {
+                while self.eval_bool(e)? {
+                    match self.exec(body)? {
+                        Flow::Normal | Flow::Continue => (),
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
                     }
-                    self.execute(*body.clone());
                 }
-            },
-            Stmt::Assign(_, _, _) => panic!("not implemented"),
-            Stmt::Call(_) => panic!("not implemented"),
-            Stmt::Return(_) => panic!("not implemented"),
+            }
+            StmtKind::For(ident, start, end, body) => {
+                let start = self.eval(start)?;
+                let end = self.eval(end)?;
+                let end = as_int(end)?;
+                let mut scope = HashMap::new();
+                scope.insert(ident.node, start);
+                self.scopes().push(scope);
+                let flow = self.exec_for(ident.node, end, body);
+                self.scopes().pop();
+                return flow;
+            }
+            StmtKind::Break => return Ok(Flow::Break),
+            StmtKind::Continue => return Ok(Flow::Continue),
+            StmtKind::Call(e) => {
+                self.eval(e)?;
+            }
+            StmtKind::Return(e) => {
+                let v = self.eval(e)?;
+                return Ok(Flow::Return(v));
+            }
         }
+        Ok(Flow::Normal)
     }
-    fn eval(&mut self, expr: Expr) -> i64 {
-        fn bool_to_i64(v: bool) -> i64 {
-            if v {
-                1
-            } else {
-                0
+
+    fn exec_block(&mut self, stmts: &[Stmt]) -> Result<Flow> {
+        for s in stmts {
+            match self.exec(s)? {
+                Flow::Normal => (),
+                flow => return Ok(flow),
             }
         }
-        match expr {
-            Expr::Number(v) => v,
-            Expr::EnvLoad(ident) => match self.env.get(&ident) {
+        Ok(Flow::Normal)
+    }
+
+    // the counter is re-read after each iteration, so assignments to it in the body take effect
+    fn exec_for(&mut self, counter: Ident, end: i64, body: &Stmt) -> Result<Flow> {
+        while as_int(self.lookup(counter).unwrap().clone())? < end {
+            match self.exec(body)? {
+                Flow::Normal | Flow::Continue => (),
+                Flow::Break => break,
+                Flow::Return(v) => return Ok(Flow::Return(v)),
+            }
+            let i = self.lookup(counter).unwrap();
+            *i = Value::Int(as_int(i.clone())?.wrapping_add(1));
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn eval_bool(&mut self, expr: &Expr) -> Result<bool> {
+        match self.eval(expr)? {
+            Value::Bool(v) => Ok(v),
+            v => Err(type_mismatch("bool", &v)),
+        }
+    }
+
+    // Evaluates the array and the index, without checking the bounds.
+    fn eval_index(&mut self, array: &Expr, index: &Expr) -> Result<(Rc<RefCell<Vec<Value>>>, i64)> {
+        let elements = match self.eval(array)? {
+            Value::Array(elements) => elements,
+            v => return Err(type_mismatch("array", &v)),
+        };
+        let index = as_int(self.eval(index)?)?;
+        Ok((elements, index))
+    }
+
+    fn eval_record(&mut self, expr: &Expr) -> Result<Rc<Record>> {
+        match self.eval(expr)? {
+            Value::Record(record) => Ok(record),
+            v => Err(type_mismatch("record", &v)),
+        }
+    }
+
+    fn field_offset(&self, record: &Record, field: &SpannedIdent) -> Result<usize> {
+        self.structs[&record.name]
+            .iter()
+            .position(|ident| *ident == field.node)
+            .ok_or_else(|| unresolved("field", field.span))
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value> {
+        Ok(match &expr.node {
+            ExprKind::Number(v) => Value::Int(*v),
+            ExprKind::Bool(v) => Value::Bool(*v),
+            ExprKind::Str(s) => Value::Str(s.as_str().into()),
+            ExprKind::Array(exprs) => {
+                let elements = exprs.iter().map(|e| self.eval(e)).collect::<Result<_>>()?;
+                Value::Array(Rc::new(RefCell::new(elements)))
+            }
+            ExprKind::Index(array, index) => {
+                let (elements, index) = self.eval_index(array, index)?;
+                let index = check_index(&elements, index)?;
+                let element = elements.borrow()[index].clone();
+                element
+            }
+            ExprKind::Struct(name, fields) => {
+                let declared = match self.structs.get(&name.node) {
+                    Some(declared) => declared.clone(),
+                    None => return Err(unresolved("struct", name.span)),
+                };
+                // evaluated in the order of the declaration, like the compiled code
+                let mut values = Vec::new();
+                for ident in &declared {
+                    match fields.iter().find(|(field, _)| field.node == *ident) {
+                        Some((_, e)) => values.push(self.eval(e)?),
+                        None => return Err(unresolved("field", name.span)),
+                    }
+                }
+                Value::Record(Rc::new(Record {
+                    name: name.node,
+                    fields: RefCell::new(values),
+                }))
+            }
+            ExprKind::Field(record, field) => {
+                let record = self.eval_record(record)?;
+                let offset = self.field_offset(&record, field)?;
+                let v = record.fields.borrow()[offset].clone();
+                v
+            }
+            ExprKind::EnvLoad(ident) => match self.lookup(*ident) {
                 Some(v) => v.clone(),
-                None => panic!("not in env: {:?}", ident),
-            },
-            Expr::Op(a, opcode, b) => match opcode {
-                Opcode::Add => self.eval(*a) + self.eval(*b),
-                Opcode::Sub => self.eval(*a) - self.eval(*b),
-                Opcode::Mul => self.eval(*a) * self.eval(*b),
-                Opcode::Div => self.eval(*a) / self.eval(*b),
-                Opcode::Or => bool_to_i64(self.eval(*a) != 0 || self.eval(*b) != 0),
-                Opcode::And => bool_to_i64(self.eval(*a) != 0 && self.eval(*b) != 0),
-                Opcode::Equal => bool_to_i64(self.eval(*a) == self.eval(*b)),
-                Opcode::NotEqual => bool_to_i64(self.eval(*a) != self.eval(*b)),
-                Opcode::LessThan => bool_to_i64(self.eval(*a) < self.eval(*b)),
-                Opcode::LessEqual => bool_to_i64(self.eval(*a) <= self.eval(*b)),
-                Opcode::GreaterThan => bool_to_i64(self.eval(*a) > self.eval(*b)),
-                Opcode::GreaterEqual => bool_to_i64(self.eval(*a) >= self.eval(*b)),
+                None => match self.functions.get(ident) {
+                    Some(function) => Value::Function(Rc::new(Closure {
+                        function: function.clone(),
+                        captures: HashMap::new(),
+                    })),
+                    None => return Err(self.unbound(*ident, expr.span)),
+                },
             },
-            Expr::Call(_, _) => panic!("not implemented"),
-            Expr::Error => 666,
+            ExprKind::Op(a, Opcode::And, b) => {
+                Value::Bool(self.eval_bool(a)? && self.eval_bool(b)?)
+            }
+            ExprKind::Op(a, Opcode::Or, b) => Value::Bool(self.eval_bool(a)? || self.eval_bool(b)?),
+            ExprKind::Op(a, opcode, b) => {
+                let a = self.eval(a)?;
+                let b = self.eval(b)?;
+                binop(*opcode, a, b)?
+            }
+            ExprKind::Unary(op, e) => {
+                let v = self.eval(e)?;
+                match (op, v) {
+                    (UnOp::Neg, Value::Int(v)) => Value::Int(v.wrapping_neg()),
+                    (UnOp::Complement, Value::Int(v)) => Value::Int(!v),
+                    (UnOp::Not, Value::Bool(v)) => Value::Bool(!v),
+                    (UnOp::Len, Value::Str(s)) => Value::Int(s.chars().count() as i64),
+                    (UnOp::Len, Value::Array(elements)) => {
+                        Value::Int(elements.borrow().len() as i64)
+                    }
+                    (UnOp::Not, v) => return Err(type_mismatch("bool", &v)),
+                    (UnOp::Len, v) => return Err(type_mismatch("string or array", &v)),
+                    (_, v) => return Err(type_mismatch("int", &v)),
+                }
+            }
+            ExprKind::Function(args, body) => {
+                let function = Function {
+                    args: args.iter().map(|a| a.node).collect(),
+                    body: (**body).clone(),
+                };
+                let mut captures = HashMap::new();
+                for scope in self.scopes().iter() {
+                    captures.extend(scope.iter().map(|(k, v)| (*k, v.clone())));
+                }
+                Value::Function(Rc::new(Closure {
+                    function: Rc::new(function),
+                    captures,
+                }))
+            }
+            ExprKind::Call(name, exprs) => {
+                // like the compiled code, the function value is loaded after the arguments
+                let values = exprs
+                    .iter()
+                    .map(|e| self.eval(e))
+                    .collect::<Result<Vec<_>>>()?;
+                // local bindings shadow declared functions
+                let (function, captures) = match self.lookup(name.node) {
+                    Some(Value::Function(closure)) => {
+                        (closure.function.clone(), closure.captures.clone())
+                    }
+                    Some(v) => return Err(type_mismatch("function", v)),
+                    None => match self.functions.get(&name.node) {
+                        Some(function) => (function.clone(), HashMap::new()),
+                        None => return Err(unresolved("function", name.span)),
+                    },
+                };
+                if function.args.len() != exprs.len() {
+                    return Err(Error::Resolve(
+                        format!(
+                            "function expects {} arguments, found {}",
+                            function.args.len(),
+                            exprs.len()
+                        ),
+                        expr.span,
+                    ));
+                }
+                let args = function.args.iter().copied().zip(values).collect();
+                // the first frame is the toplevel
+                if self.frames.len() > self.max_depth {
+                    return Err(Trap::CallDepthExceeded {
+                        limit: self.max_depth,
+                    }
+                    .into());
+                }
+                self.frames.push(vec![captures, args]);
+                let res = self.exec(&function.body);
+                self.frames.pop();
+                res?.check_loop(function.body.span)?
+                    .unwrap_or(Value::Int(0))
+            }
+            ExprKind::Error => {
+                return Err(Error::Parse(
+                    "found Expr::Error in eval".into(),
+                    Some(expr.span),
+                ))
+            }
+        })
+    }
+}
+
+// `typeck::check` rejects unresolved names before a program runs, the evaluator only finds them in
+// programs that skipped it. It has no access to the interner, so the name is not part of the error.
+fn unresolved(what: &str, span: Span) -> Error {
+    Error::Resolve(
+        format!(
+            "internal error: unresolved {} (the program was not type checked)",
+            what
+        ),
+        span,
+    )
+}
+
+fn type_mismatch(expected: &'static str, found: &Value) -> Error {
+    Trap::TypeMismatch {
+        expected,
+        found: found.type_name(),
+    }
+    .into()
+}
+
+fn check_index(elements: &RefCell<Vec<Value>>, index: i64) -> Result<usize> {
+    let len = elements.borrow().len();
+    if index < 0 || index as usize >= len {
+        return Err(Trap::IndexOutOfBounds { index, len }.into());
+    }
+    Ok(index as usize)
+}
+
+fn as_int(v: Value) -> Result<i64> {
+    match v {
+        Value::Int(v) => Ok(v),
+        v => Err(type_mismatch("int", &v)),
+    }
+}
+
+// `and` / `or` are evaluated lazily by `Evaluator::eval` and never get here
+fn binop(opcode: Opcode, a: Value, b: Value) -> Result<Value> {
+    match (opcode, a, b) {
+        (Opcode::Equal, a, b) | (Opcode::NotEqual, a, b) => {
+            if a.type_name() != b.type_name() {
+                return Err(type_mismatch(a.type_name(), &b));
+            }
+            Ok(Value::Bool((a == b) == matches!(opcode, Opcode::Equal)))
         }
+        (Opcode::Add, Value::Str(a), Value::Str(b)) => Ok(Value::Str((a.to_string() + &b).into())),
+        (_, Value::Int(a), Value::Int(b)) => int_binop(opcode, a, b),
+        (_, Value::Int(_), b) => Err(type_mismatch("int", &b)),
+        (_, a, _) => Err(type_mismatch("int", &a)),
+    }
+}
+
+fn int_binop(opcode: Opcode, a: i64, b: i64) -> Result<Value> {
+    Ok(Value::Int(match opcode {
+        Opcode::Add => a.wrapping_add(b),
+        Opcode::Sub => a.wrapping_sub(b),
+        Opcode::Mul => a.wrapping_mul(b),
+        Opcode::Div if b == 0 => return Err(Trap::DivisionByZero.into()),
+        Opcode::Div => a.wrapping_div(b),
+        Opcode::Mod if b == 0 => return Err(Trap::DivisionByZero.into()),
+        Opcode::Mod => a.wrapping_rem(b),
+        Opcode::BitAnd => a & b,
+        Opcode::BitOr => a | b,
+        Opcode::BitXor => a ^ b,
+        // shift amounts are taken modulo 64, as in the VM
+        Opcode::Shl => a.wrapping_shl(b as u32),
+        Opcode::Shr => a.wrapping_shr(b as u32),
+        Opcode::Ushr => (a as u64).wrapping_shr(b as u32) as i64,
+        Opcode::LessThan => return Ok(Value::Bool(a < b)),
+        Opcode::LessEqual => return Ok(Value::Bool(a <= b)),
+        Opcode::GreaterThan => return Ok(Value::Bool(a > b)),
+        Opcode::GreaterEqual => return Ok(Value::Bool(a >= b)),
+        Opcode::Or | Opcode::And | Opcode::Equal | Opcode::NotEqual => unreachable!(),
+    }))
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Evaluator;
+    use crate::error::Trap;
+    use crate::lang1;
+    use handy::HandleMap;
+    use std::sync::mpsc::channel;
+
+    fn run(code: &str) -> Vec<String> {
+        let mut env = HandleMap::new();
+        let mut errors = Vec::new();
+        let program = lang1::ProgramParser::new()
+            .parse(&mut env, &mut errors, code)
+            .unwrap();
+        let (send, recv) = channel();
+        let mut evaluator = Evaluator::new();
+        evaluator.output = Some(send);
+        evaluator.run(&program).unwrap();
+        recv.try_iter().collect()
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            run(include_str!("../data/test_factorial.l1")),
+            [10, 3628800].map(|v| v.to_string())
+        );
+        assert_eq!(
+            run(include_str!("../data/test_decl.l1")),
+            [1, 2, 8, 666, 4711, 123, 4321].map(|v| v.to_string())
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        assert_eq!(
+            run(include_str!("../data/test_scope.l1")),
+            [321, 432, 123, 123, 321, 666, 999].map(|v| v.to_string())
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(
+            run("let a = [1, 2]; a[1] += a[0]; print a, len(a);"),
+            ["[1, 3]", "2"]
+        );
+        let mut env = HandleMap::new();
+        let mut errors = Vec::new();
+        let program = lang1::ProgramParser::new()
+            .parse(&mut env, &mut errors, "let a = [1, 2]; a[2] = 3;")
+            .unwrap();
+        assert_eq!(
+            Evaluator::new().run(&program),
+            Err(Trap::IndexOutOfBounds { index: 2, len: 2 }.into())
+        );
+    }
+
+    #[test]
+    fn call_depth() {
+        // runs with the stack size of the main thread, which the default limit is made for
+        let depth = std::thread::Builder::new()
+            .stack_size(8 * 1024 * 1024)
+            .spawn(|| {
+                let mut env = HandleMap::new();
+                let mut errors = Vec::new();
+                let program = lang1::ProgramParser::new()
+                    .parse(
+                        &mut env,
+                        &mut errors,
+                        "fn rec(n) { if n == 0 { return 0; } return rec(n - 1) + 1; } print rec(100000);",
+                    )
+                    .unwrap();
+                Evaluator::new().run(&program)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(
+            depth,
+            Err(Trap::CallDepthExceeded {
+                limit: super::MAX_DEPTH
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn unresolved() {
+        let mut env = HandleMap::new();
+        let mut errors = Vec::new();
+        let program = lang1::ProgramParser::new()
+            .parse(&mut env, &mut errors, "print x;")
+            .unwrap();
+        assert_eq!(
+            Evaluator::new().run(&program).unwrap_err().to_string(),
+            "resolve error: internal error: unresolved variable (the program was not type checked)"
+        );
+
+        let program = lang1::ProgramParser::new()
+            .parse(&mut env, &mut errors, "import \"util.l1\"; print 1;")
+            .unwrap();
+        let err = Evaluator::new().run(&program).unwrap_err().to_string();
+        assert!(err.starts_with("module error: cannot import `util.l1` into a single source"));
+    }
+
+    #[test]
+    fn unbound_globals() {
+        let run_err = |code| {
+            let mut env = HandleMap::new();
+            let mut errors = Vec::new();
+            let program = lang1::ProgramParser::new()
+                .parse(&mut env, &mut errors, code)
+                .unwrap();
+            Evaluator::new().run(&program).unwrap_err()
+        };
+        let code = "fn f() { return g; } print f(); let g = 1;";
+        assert_eq!(run_err(code), Trap::InvalidGlobal(0).into());
+        let code = "let a = 1; fn f() { g += a; } f(); let g = 1;";
+        assert_eq!(run_err(code), Trap::InvalidGlobal(1).into());
+
+        let code = "fn set() { g = 2; } fn get() { return g; } set(); print get(); let g = 1;";
+        assert_eq!(run(code), ["2"]);
+    }
+
+    #[test]
+    fn early_return() {
+        let code = "
+            fn first_multiple(n, m) {
+                let i = 1;
+                while i < 100 {
+                    if i / m * m == i {
+                        if i > n {
+                            return i;
+                        }
+                    }
+                    i = i + 1;
+                }
+                return 0;
+            }
+            print first_multiple(10, 7);
+        ";
+        assert_eq!(run(code), [14].map(|v| v.to_string()));
     }
 }
@@ -0,0 +1,197 @@
+use crate::bytecode::Value;
+use crate::error::Trap;
+use log::debug;
+
+/// Objects on the VM heap, referenced by index from [`Value`]s.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    Str(String),
+    Array(Vec<Value>),
+    /// The fields of a struct, in order of declaration.
+    Record(Vec<Value>),
+    /// A function value: the code address of the function and the values of the variables it
+    /// captured when it was created.
+    Closure {
+        address: usize,
+        captures: Vec<Value>,
+    },
+}
+
+impl Object {
+    /// Values stored in the object, which keep other objects alive.
+    pub fn references(&self) -> &[Value] {
+        match self {
+            Object::Str(_) => &[],
+            Object::Array(elements) | Object::Record(elements) => elements,
+            Object::Closure { captures, .. } => captures,
+        }
+    }
+}
+
+/// Counters of the garbage collector, reported next to [`crate::bytecode::Vm::num_ops`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    /// Number of objects allocated so far.
+    pub allocated: usize,
+    /// Number of objects reclaimed so far.
+    pub freed: usize,
+    /// Highest number of objects alive at the same time.
+    pub max_live: usize,
+}
+
+// collect once this many objects are alive, the threshold grows with the live objects
+const MIN_THRESHOLD: usize = 256;
+
+/// Heap of the VM with a non-moving mark-and-sweep collector. Objects keep their index for their
+/// whole lifetime; the slots of reclaimed objects are reused by later allocations.
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    free: Vec<usize>,
+    live: usize,
+    threshold: usize,
+    /// Maximum number of objects alive at the same time. Allocating more traps with
+    /// [`Trap::OutOfMemory`] if a collection cannot make room.
+    pub limit: Option<usize>,
+    pub stats: GcStats,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            free: Vec::new(),
+            live: 0,
+            threshold: MIN_THRESHOLD,
+            limit: None,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn get(&self, r: usize) -> Option<&Object> {
+        self.objects.get(r).and_then(Option::as_ref)
+    }
+
+    pub fn get_mut(&mut self, r: usize) -> Option<&mut Object> {
+        self.objects.get_mut(r).and_then(Option::as_mut)
+    }
+
+    /// Number of objects currently on the heap, including unreachable ones not yet collected.
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    fn is_full(&self) -> bool {
+        matches!(self.limit, Some(limit) if self.live >= limit)
+    }
+
+    /// Whether a collection should run before the next allocation.
+    pub fn needs_collection(&self) -> bool {
+        self.live >= self.threshold || self.is_full()
+    }
+
+    pub fn alloc(&mut self, object: Object) -> Result<usize, Trap> {
+        if let Some(limit) = self.limit {
+            if self.live >= limit {
+                return Err(Trap::OutOfMemory { limit });
+            }
+        }
+        self.live += 1;
+        self.stats.allocated += 1;
+        self.stats.max_live = self.stats.max_live.max(self.live);
+        match self.free.pop() {
+            Some(r) => {
+                self.objects[r] = Some(object);
+                Ok(r)
+            }
+            None => {
+                self.objects.push(Some(object));
+                Ok(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// Free all objects that cannot be reached from `roots`.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<Value> = roots.into_iter().collect();
+        while let Some(v) = pending.pop() {
+            if let Value::Str(r) | Value::Array(r) | Value::Record(r) | Value::Function(r) = v {
+                if r < marked.len() && !marked[r] {
+                    marked[r] = true;
+                    if let Some(object) = &self.objects[r] {
+                        pending.extend_from_slice(object.references());
+                    }
+                }
+            }
+        }
+
+        let mut freed = 0;
+        for (r, slot) in self.objects.iter_mut().enumerate() {
+            if slot.is_some() && !marked[r] {
+                *slot = None;
+                self.free.push(r);
+                freed += 1;
+            }
+        }
+        self.live -= freed;
+        self.threshold = MIN_THRESHOLD.max(2 * self.live);
+        self.stats.collections += 1;
+        self.stats.freed += freed;
+        debug!("gc: freed {} objects, {} alive", freed, self.live);
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect() {
+        let mut heap = Heap::new();
+        let s = heap.alloc(Object::Str("a".into())).unwrap();
+        let garbage = heap.alloc(Object::Str("b".into())).unwrap();
+        let inner = heap.alloc(Object::Array(vec![Value::Str(s)])).unwrap();
+        let outer = heap
+            .alloc(Object::Array(vec![Value::Array(inner), Value::Int(1)]))
+            .unwrap();
+        heap.collect(vec![Value::Int(7), Value::Array(outer)]);
+        assert_eq!(heap.live(), 3);
+        assert_eq!(heap.get(garbage), None);
+        assert_eq!(heap.get(s), Some(&Object::Str("a".into())));
+
+        // freed slots are reused
+        assert_eq!(heap.alloc(Object::Str("c".into())), Ok(garbage));
+        heap.collect(vec![]);
+        assert_eq!(heap.live(), 0);
+        assert_eq!(
+            heap.stats,
+            GcStats {
+                collections: 2,
+                allocated: 5,
+                freed: 5,
+                max_live: 4
+            }
+        );
+    }
+
+    #[test]
+    fn limit() {
+        let mut heap = Heap::new();
+        heap.limit = Some(1);
+        let r = heap.alloc(Object::Str("a".into())).unwrap();
+        assert!(heap.needs_collection());
+        assert_eq!(
+            heap.alloc(Object::Str("b".into())),
+            Err(Trap::OutOfMemory { limit: 1 })
+        );
+        heap.collect(vec![]);
+        assert_eq!(heap.alloc(Object::Str("b".into())), Ok(r));
+    }
+}
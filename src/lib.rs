@@ -6,8 +6,15 @@ extern crate lalrpop_util;
 pub mod asm;
 pub mod ast;
 pub mod bytecode;
+pub mod compile;
+pub mod diag;
+pub mod error;
 pub mod eval;
+pub mod heap;
+pub mod module;
 pub mod parser;
+pub mod repl;
+pub mod typeck;
 
 lalrpop_mod!(pub lang1);
 
@@ -38,14 +45,14 @@ mod test {
             crate::ast::Toplevel::Stmt(s) => Some(s),
             _ => None,
         }) {
-            evaluator.execute(s.clone());
+            evaluator.execute(s).unwrap();
         }
 
         let expr = lang1::ProgramParser::new()
             .parse(
                 &mut env,
                 &mut errors,
-                "let a = 41 + 1; if a {print 10 * 10;} else {print 123 * 0b101, a;}",
+                "let a = 41 + 1; if a == 42 {print 10 * 10;} else {print 123 * 0b101, a;}",
             )
             .unwrap();
         for s in expr.iter().filter_map(|x| match x {
@@ -53,7 +60,7 @@ mod test {
             _ => None,
         }) {
             println!("execute: {:?}", s);
-            evaluator.execute(s.clone());
+            evaluator.execute(s).unwrap();
         }
     }
 
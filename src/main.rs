@@ -1,3 +1,20 @@
+use lalrpop_test::repl::Repl;
+use std::io::{BufRead, Write};
+
 fn main() {
-    println!("Hello, world!");
+    env_logger::init();
+
+    let mut repl = Repl::new();
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", if repl.is_continued() { "... " } else { ">>> " });
+        std::io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(line) => line.unwrap(),
+            None => break,
+        };
+        print!("{}", repl.line(&line));
+    }
+    println!();
 }
@@ -0,0 +1,571 @@
+use crate::{
+    ast::{
+        Declaration, Expr, ExprKind, HandleMapDedup, Ident, Span, Spanned, SpannedIdent, Stmt,
+        StmtKind, Toplevel,
+    },
+    compile::Compiler,
+    diag::SourceFile,
+    error::Error,
+};
+use handy::HandleMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// A source file of a program.
+pub struct Module {
+    /// The file stem, which qualifies the toplevel names of the module in its importers
+    /// (`util::name`).
+    pub name: String,
+    pub path: PathBuf,
+    pub source: String,
+    // the modules share one offset space, so that a span tells which file it belongs to
+    base: usize,
+    imports: Vec<usize>,
+    // toplevel functions, structs and globals, qualified with the module name
+    exports: Vec<String>,
+}
+
+/// A program and the modules it imports, transitively. Files are resolved relative to the
+/// importing file and loaded once, no matter how often they are imported.
+///
+/// The toplevel names of imported modules are qualified with the module name, so that they do not
+/// clash with the names of other modules; the main module keeps its names as written. Parsing links
+/// all modules into a single program, dependencies first, which can then be type checked and
+/// compiled like a program from a single file.
+#[derive(Default)]
+pub struct Modules {
+    // in the order they were loaded, the main module first
+    modules: Vec<Module>,
+    // indices of the modules in dependency order, the main module last
+    order: Vec<usize>,
+}
+
+impl Modules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the program in the file at `path` and the modules it imports.
+    pub fn load(&mut self, path: &Path) -> Result<(), Vec<Error>> {
+        self.load_with(path, None, |path| std::fs::read_to_string(path))
+    }
+
+    /// Load a program with `read` as file system. The source of the main module is read from `path`
+    /// unless it is given; its imports are resolved relative to `path` in any case.
+    ///
+    /// The modules loaded before an error are kept, so that the error can be rendered.
+    pub fn load_with(
+        &mut self,
+        path: &Path,
+        source: Option<String>,
+        mut read: impl FnMut(&Path) -> io::Result<String>,
+    ) -> Result<(), Vec<Error>> {
+        self.modules.clear();
+        self.order.clear();
+        self.visit(&mut read, &mut Vec::new(), normalize(path), source, None)?;
+        Ok(())
+    }
+
+    pub fn main(&self) -> &Module {
+        &self.modules[0]
+    }
+
+    /// Parse all modules and link them into one program.
+    pub fn parse<'a>(&'a self, env: &mut HandleMap<&'a str>) -> Result<Vec<Toplevel>, Vec<Error>> {
+        let compiler = Compiler::new();
+        let mut linked = Vec::new();
+        let mut errors = Vec::new();
+        for &i in &self.order {
+            let module = &self.modules[i];
+            // the modules have been parsed successfully while loading
+            let program = compiler
+                .parse(env, &module.source)
+                .unwrap_or_else(|_| panic!("{} failed to parse", module.path.display()));
+            let mut names = HashMap::new();
+            if i != 0 {
+                for qualified in &module.exports {
+                    let plain = &qualified[module.name.len() + 2..];
+                    names.insert(env.get_dedup(plain), env.get_dedup(&qualified[..]));
+                }
+            }
+            let mut linker = Linker {
+                env,
+                module: &module.name,
+                imports: module
+                    .imports
+                    .iter()
+                    .map(|&i| &self.modules[i].name[..])
+                    .collect(),
+                names,
+                scopes: Vec::new(),
+                errors: &mut errors,
+            };
+            for mut toplevel in program {
+                toplevel.offset(module.base);
+                linker.toplevel(&mut toplevel);
+                if !matches!(toplevel, Toplevel::Declaration(Declaration::Import(_))) {
+                    linked.push(toplevel);
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(linked)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Render an error like [`SourceFile::render`], against the module its span points into.
+    pub fn render(&self, err: &Error) -> String {
+        let span = err.span();
+        let module = span.and_then(|span| {
+            self.modules
+                .iter()
+                .find(|m| (m.base..=m.base + m.source.len()).contains(&span.start))
+        });
+        let (span, module) = match (span, module) {
+            (Some(span), Some(module)) => (span, module),
+            _ => return format!("{}\n", err),
+        };
+        let name = module.path.display().to_string();
+        let span = Span::new(span.start - module.base, span.end - module.base);
+        SourceFile::new(&name, &module.source).render_span(&err.to_string(), span)
+    }
+
+    // Loads the module at `path`, unless it has been loaded already, and then its imports.
+    fn visit(
+        &mut self,
+        read: &mut impl FnMut(&Path) -> io::Result<String>,
+        stack: &mut Vec<usize>,
+        path: PathBuf,
+        source: Option<String>,
+        import: Option<Span>,
+    ) -> Result<usize, Vec<Error>> {
+        if let Some(i) = self.modules.iter().position(|m| m.path == path) {
+            if let Some(start) = stack.iter().position(|&s| s == i) {
+                let cycle: Vec<_> = stack[start..]
+                    .iter()
+                    .chain(Some(&i))
+                    .map(|&s| self.modules[s].path.display().to_string())
+                    .collect();
+                let msg = format!("import cycle: {}", cycle.join(" -> "));
+                return Err(vec![Error::Module(msg, import)]);
+            }
+            return Ok(i);
+        }
+        let source = match source {
+            Some(source) => source,
+            None => read(&path).map_err(|err| {
+                let msg = format!("cannot read `{}`: {}", path.display(), err);
+                vec![Error::Module(msg, import)]
+            })?,
+        };
+        let name = path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        // the names of the main module are not qualified, so it cannot clash
+        if let Some(other) = self.modules.iter().skip(1).find(|m| m.name == name) {
+            let msg = format!(
+                "module `{}` at `{}` has the same name as `{}`",
+                name,
+                path.display(),
+                other.path.display()
+            );
+            return Err(vec![Error::Module(msg, import)]);
+        }
+        let base = self
+            .modules
+            .last()
+            .map_or(0, |m| m.base + m.source.len() + 1);
+        let i = self.modules.len();
+        self.modules.push(Module {
+            name,
+            path,
+            source,
+            base,
+            imports: Vec::new(),
+            exports: Vec::new(),
+        });
+
+        // the imports are loaded while the names of this module are looked up, so they are
+        // interned from a copy of the source
+        let (source, path) = (self.modules[i].source.clone(), &self.modules[i].path);
+        let dir = path.parent().unwrap_or_else(|| Path::new("")).to_owned();
+        let mut env = HandleMap::new();
+        let program = Compiler::new().parse(&mut env, &source).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|e| offset(e, base))
+                .collect::<Vec<_>>()
+        })?;
+        let mut exports = Vec::new();
+        let mut imports = Vec::new();
+        stack.push(i);
+        for toplevel in &program {
+            let ident = match toplevel {
+                Toplevel::Declaration(Declaration::Function(name, ..))
+                | Toplevel::Declaration(Declaration::Struct(name, _)) => name.node,
+                Toplevel::Stmt(Stmt {
+                    node: StmtKind::LetBinding(name, _),
+                    ..
+                }) => name.node,
+                Toplevel::Declaration(Declaration::Import(file)) => {
+                    let file_path = normalize(&dir.join(&file.node));
+                    let span = Some(file.span.offset(base));
+                    imports.push(self.visit(read, stack, file_path, None, span)?);
+                    continue;
+                }
+                Toplevel::Stmt(_) => continue,
+            };
+            let module = &self.modules[i];
+            exports.push(format!("{}::{}", module.name, env.get(ident).unwrap()));
+        }
+        stack.pop();
+
+        self.modules[i].imports = imports;
+        self.modules[i].exports = exports;
+        self.order.push(i);
+        Ok(i)
+    }
+}
+
+// Resolves `.` and `..` without touching the file system, so that a module imported along
+// different paths is loaded once.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn offset(err: Error, base: usize) -> Error {
+    match err {
+        Error::Parse(msg, span) => Error::Parse(msg, span.map(|span| span.offset(base))),
+        err => err,
+    }
+}
+
+// Qualifies the references of a module to its own toplevel names. References to names of other modules are already qualified in the source,
+// those modules must be imported.
+struct Linker<'l, 'a> {
+    env: &'l HandleMap<&'a str>,
+    module: &'l str,
+    imports: HashSet<&'l str>,
+    // unqualified name -> qualified name of the toplevel names of the module
+    names: HashMap<Ident, Ident>,
+    // local bindings, which shadow toplevel names
+    scopes: Vec<HashSet<Ident>>,
+    errors: &'l mut Vec<Error>,
+}
+
+impl Linker<'_, '_> {
+    fn toplevel(&mut self, toplevel: &mut Toplevel) {
+        match toplevel {
+            Toplevel::Stmt(stmt) => self.stmt(stmt),
+            Toplevel::Declaration(Declaration::Function(name, args, body)) => {
+                self.declare(name);
+                let scopes = std::mem::take(&mut self.scopes);
+                self.scopes.push(HashSet::new());
+                for arg in args {
+                    self.bind(arg);
+                }
+                self.stmt(body);
+                self.scopes = scopes;
+            }
+            Toplevel::Declaration(Declaration::Struct(name, _)) => self.declare(name),
+            Toplevel::Declaration(Declaration::Import(_)) => (),
+        }
+    }
+
+    fn declare(&mut self, ident: &mut SpannedIdent) {
+        if let Some(&qualified) = self.names.get(&ident.node) {
+            ident.node = qualified;
+        }
+    }
+
+    // toplevel `let`s outside of blocks declare globals, all others local bindings
+    fn bind(&mut self, ident: &mut SpannedIdent) {
+        match self.scopes.last_mut() {
+            Some(scope) => {
+                scope.insert(ident.node);
+            }
+            None => self.declare(ident),
+        }
+    }
+
+    fn reference(&mut self, ident: &mut Ident, span: Span) {
+        if self.scopes.iter().any(|scope| scope.contains(ident)) {
+            return;
+        }
+        if let Some(&qualified) = self.names.get(ident) {
+            *ident = qualified;
+            return;
+        }
+        let name = self.env.get(*ident).unwrap();
+        if let Some((module, _)) = name.split_once("::") {
+            if module != self.module && !self.imports.contains(module) {
+                let msg = format!("module `{}` is not imported", module);
+                self.errors.push(Error::Resolve(msg, span));
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.node {
+            StmtKind::LetBinding(ident, expr) => {
+                self.expr(expr);
+                self.bind(ident);
+            }
+            StmtKind::Assign(ident, expr, _) => {
+                self.reference(&mut ident.node, ident.span);
+                self.expr(expr);
+            }
+            StmtKind::AssignIndex(array, index, expr, _) => {
+                self.expr(array);
+                self.expr(index);
+                self.expr(expr);
+            }
+            StmtKind::AssignField(record, _, expr, _) => {
+                self.expr(record);
+                self.expr(expr);
+            }
+            StmtKind::Print(exprs) => exprs.iter_mut().for_each(|expr| self.expr(expr)),
+            StmtKind::IfElse(cond, if_body, else_body) => {
+                self.expr(cond);
+                self.stmt(if_body);
+                if let Some(else_body) = else_body {
+                    self.stmt(else_body);
+                }
+            }
+            StmtKind::While(cond, body) => {
+                self.expr(cond);
+                self.stmt(body);
+            }
+            StmtKind::For(ident, start, end, body) => {
+                self.expr(start);
+                self.expr(end);
+                self.scopes.push(HashSet::new());
+                self.bind(ident);
+                self.stmt(body);
+                self.scopes.pop();
+            }
+            StmtKind::Break | StmtKind::Continue => (),
+            StmtKind::Block(stmts) => {
+                self.scopes.push(HashSet::new());
+                stmts.iter_mut().for_each(|stmt| self.stmt(stmt));
+                self.scopes.pop();
+            }
+            StmtKind::Call(expr) | StmtKind::Return(expr) => self.expr(expr),
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match &mut expr.node {
+            ExprKind::Number(_) | ExprKind::Bool(_) | ExprKind::Str(_) | ExprKind::Error => (),
+            ExprKind::EnvLoad(ident) => self.reference(ident, expr.span),
+            ExprKind::Array(elements) => elements.iter_mut().for_each(|e| self.expr(e)),
+            ExprKind::Index(array, index) => {
+                self.expr(array);
+                self.expr(index);
+            }
+            ExprKind::Struct(name, fields) => {
+                self.reference(&mut name.node, name.span);
+                for (_, value) in fields {
+                    self.expr(value);
+                }
+            }
+            ExprKind::Field(record, _) => self.expr(record),
+            ExprKind::Op(l, _, r) => {
+                self.expr(l);
+                self.expr(r);
+            }
+            ExprKind::Unary(_, e) => self.expr(e),
+            ExprKind::Call(name, args) => {
+                self.reference(&mut name.node, name.span);
+                args.iter_mut().for_each(|arg| self.expr(arg));
+            }
+            ExprKind::Function(args, body) => {
+                self.scopes.push(HashSet::new());
+                for arg in args {
+                    self.bind(arg);
+                }
+                self.stmt(body);
+                self.scopes.pop();
+            }
+        }
+    }
+}
+
+/// Error for an `import` outside of [`Modules`], the only place where imports are resolved.
+pub fn import_error(file: &Spanned<String>) -> Error {
+    let msg = format!(
+        "cannot import `{}` into a single source, imports are resolved by the module loader (`module::Modules`)",
+        file.node
+    );
+    Error::Module(msg, Some(file.span))
+}
+
+/// Fail on the first `import` of a program that was not linked by [`Modules`].
+pub fn reject_imports(program: &[Toplevel]) -> Result<(), Error> {
+    let import = program.iter().find_map(|toplevel| match toplevel {
+        Toplevel::Declaration(Declaration::Import(file)) => Some(file),
+        _ => None,
+    });
+    match import {
+        Some(file) => Err(import_error(file)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Modules;
+    use crate::bytecode::{IoChannels, Vm};
+    use crate::error::Error;
+    use crate::{asm, compile::Compiler, typeck};
+    use handy::HandleMap;
+    use std::io;
+    use std::path::Path;
+    use std::sync::mpsc::channel;
+
+    fn load(files: &[(&str, &str)]) -> (Modules, Result<(), Vec<Error>>) {
+        let mut modules = Modules::new();
+        let res = modules.load_with(Path::new(files[0].0), None, |path| {
+            files
+                .iter()
+                .find(|(name, _)| Path::new(name) == path)
+                .map(|(_, source)| source.to_string())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+        });
+        (modules, res)
+    }
+
+    fn run(files: &[(&str, &str)]) -> Vec<String> {
+        let (modules, res) = load(files);
+        res.unwrap();
+        let mut env = HandleMap::new();
+        let program = modules.parse(&mut env).unwrap();
+        typeck::check(&env, &program).unwrap();
+        let sections = Compiler::new().compile(&env, &program).unwrap();
+        let (send, recv) = channel();
+        let mut io = IoChannels::new();
+        io.channels.push(send);
+        let mut vm = Vm::from_program(asm::assemble(&sections).unwrap());
+        vm.exec(Some(&io)).unwrap();
+        recv.try_iter().collect()
+    }
+
+    // errors of loading or linking, rendered against their files
+    fn errors(files: &[(&str, &str)]) -> Vec<String> {
+        let (modules, res) = load(files);
+        let mut env = HandleMap::new();
+        let errors = match res.and_then(|_| modules.parse(&mut env)) {
+            Ok(program) => typeck::check(&env, &program).unwrap_err(),
+            Err(errors) => errors,
+        };
+        errors.iter().map(|err| modules.render(err)).collect()
+    }
+
+    #[test]
+    fn link() {
+        let files = [
+            (
+                "main.l1",
+                "import \"lib/util.l1\";
+                 import \"lib/../math.l1\";
+                 fn double(x) { return 0; }
+                 let p = util::Point { x: util::double(util::base) };
+                 print p.x, math::add(1, 2), double(1);
+                 util::base += 1;
+                 print util::base, util::bump();",
+            ),
+            (
+                "lib/util.l1",
+                "import \"../math.l1\";
+                 struct Point { x }
+                 let base = 20;
+                 fn double(x) { return math::add(x, x) + one(); }
+                 fn one() { return 1; }
+                 fn bump() { base += 1; let base = 0; return base; }
+                 print \"util\";",
+            ),
+            ("math.l1", "fn add(a, b) { return a + b; } print \"math\";"),
+        ];
+        assert_eq!(
+            run(&files),
+            ["math", "util", "41", "3", "0", "21", "0"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn load_errors() {
+        let cycle = [
+            ("main.l1", "import \"a.l1\";"),
+            ("a.l1", "import \"b.l1\";"),
+            ("b.l1", "print 1;\nimport \"a.l1\";"),
+        ];
+        assert_eq!(
+            errors(&cycle),
+            ["module error: import cycle: a.l1 -> b.l1 -> a.l1\n --> b.l1:2:8\n  |\n2 | import \"a.l1\";\n  |        ^^^^^^\n"]
+        );
+
+        let missing = [("main.l1", "import \"nope.l1\";")];
+        assert_eq!(
+            errors(&missing),
+            ["module error: cannot read `nope.l1`: not found\n --> main.l1:1:8\n  |\n1 | import \"nope.l1\";\n  |        ^^^^^^^^^\n"]
+        );
+
+        let syntax = [("main.l1", "import \"a.l1\";"), ("a.l1", "let = 1;")];
+        assert_eq!(errors(&syntax)[0].lines().nth(1), Some(" --> a.l1:1:5"));
+
+        let clash = [
+            ("main.l1", "import \"a/util.l1\"; import \"b/util.l1\";"),
+            ("a/util.l1", ""),
+            ("b/util.l1", ""),
+        ];
+        assert_eq!(
+            errors(&clash)[0].lines().next(),
+            Some("module error: module `util` at `b/util.l1` has the same name as `a/util.l1`")
+        );
+    }
+
+    #[test]
+    fn link_errors() {
+        let files = [
+            ("main.l1", "import \"a.l1\";\nprint a::f(), b::g();"),
+            (
+                "a.l1",
+                "import \"b.l1\";\nfn f() { return b::g() + a::h(); }",
+            ),
+            ("b.l1", "fn g() { return 1; }"),
+        ];
+        assert_eq!(
+            errors(&files),
+            [
+                "resolve error: module `b` is not imported\n --> main.l1:2:15\n  |\n2 | print a::f(), b::g();\n  |               ^^^^\n",
+            ]
+        );
+
+        let files = [
+            ("main.l1", "import \"a.l1\";"),
+            ("a.l1", "fn f() {\n  return a::g();\n}"),
+        ];
+        assert_eq!(
+            errors(&files),
+            ["resolve error: unknown identifier `a::g`\n --> a.l1:2:10\n  |\n2 |   return a::g();\n  |          ^^^^\n"]
+        );
+    }
+}
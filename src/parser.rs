@@ -1,5 +1,120 @@
-use crate::ast::{Expr, Opcode};
+use crate::ast::{Expr, ExprKind, Opcode, Span, Spanned, UnOp};
+use lalrpop_util::ParseError;
 
 pub fn binop(a: Expr, op: Opcode, b: Expr) -> Expr {
-    Expr::Op(Box::new(a), op, Box::new(b))
+    let span = a.span.to(b.span);
+    Spanned {
+        node: ExprKind::Op(Box::new(a), op, Box::new(b)),
+        span,
+    }
+}
+
+pub fn unop(start: usize, op: UnOp, e: Expr) -> Expr {
+    let span = Span::new(start, e.span.end);
+    Spanned {
+        node: ExprKind::Unary(op, Box::new(e)),
+        span,
+    }
+}
+
+/// Error for an integer literal between `start` and `end` that does not fit into an `i64`.
+pub fn out_of_range<T>(start: usize, end: usize) -> ParseError<usize, T, Spanned<&'static str>> {
+    ParseError::User {
+        error: Spanned::new("integer literal out of range", start, end),
+    }
+}
+
+/// Contents of a string literal (including the quotes), with escape sequences replaced.
+pub fn unescape(literal: &str) -> Result<String, &'static str> {
+    let mut out = String::new();
+    let mut chars = literal[1..literal.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('u') => {
+                unescape_unicode(&mut chars).ok_or("invalid escape sequence in string literal")?
+            }
+            _ => return Err("invalid escape sequence in string literal"),
+        });
+    }
+    Ok(out)
+}
+
+// the `{..}` of a `\u{..}` escape with 1 to 6 hex digits
+fn unescape_unicode(chars: &mut std::str::Chars) -> Option<char> {
+    if chars.next() != Some('{') {
+        return None;
+    }
+    let mut code = 0;
+    for (i, c) in chars.enumerate() {
+        match c {
+            '}' if i > 0 => return std::char::from_u32(code),
+            _ if i < 6 => code = code * 16 + c.to_digit(16)?,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// A string literal (including the quotes) that `unescape` turns back into `s`. Control characters
+/// without a short escape are written as `\u{..}`.
+pub fn escape(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape, unescape};
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape(r#""abc""#), Ok("abc".to_string()));
+        assert_eq!(
+            unescape(r#""a\n\t\\\"\0b""#),
+            Ok("a\n\t\\\"\0b".to_string())
+        );
+        assert!(unescape(r#""\x""#).is_err());
+        assert_eq!(unescape(r#""\u{1b}[\u{e9}""#), Ok("\u{1b}[é".to_string()));
+        for invalid in &[
+            r#""\u1b""#,
+            r#""\u{}""#,
+            r#""\u{1b""#,
+            r#""\u{d800}""#,
+            r#""\u{1234567}""#,
+            r#""\u{+1b}""#,
+        ] {
+            assert!(unescape(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn escapes_roundtrip() {
+        for s in &["abc", "a\n\t\r\0\\\"b", "\u{1b}[0m\u{7f}", "é'ü"] {
+            assert_eq!(unescape(&escape(s)).as_deref(), Ok(*s));
+        }
+        assert_eq!(escape("a\u{1b}\"'"), r#""a\u{1b}\"'""#);
+    }
 }
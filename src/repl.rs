@@ -0,0 +1,448 @@
+use crate::{
+    asm::{self, Disass},
+    ast::{Declaration, Expr, Span, Stmt, StmtKind, Toplevel},
+    compile::Compiler,
+    diag::SourceFile,
+    error::Error,
+    eval::Evaluator,
+    lang1, module, typeck,
+};
+use handy::HandleMap;
+use lalrpop_util::ParseError;
+use std::fmt::Write;
+use std::sync::mpsc::{channel, Receiver};
+
+const HELP: &str = "\
+Enter declarations, statements or expressions. Unfinished input continues on the next line, an
+empty line cancels it.
+  :ast <code>   show the syntax tree of the code
+  :asm [code]   show the assembly of the session, followed by the code
+  :bc [code]    show the bytecode of the session, followed by the code
+  :help         show this help
+";
+
+enum Input {
+    Program(Vec<Toplevel>),
+    Exprs(Vec<Expr>),
+    // the input ends too early, with the errors to report if it is not continued
+    Incomplete(Vec<Error>),
+    Invalid(Vec<Error>),
+}
+
+/// Interactive session of lang1, backed by the [`Evaluator`]. Declarations and bindings persist
+/// across inputs; the values of expressions are shown.
+///
+/// Inputs are type checked together with the session so far before they run, so the session
+/// always remains a valid program that can be compiled. Redeclaring a function or struct replaces
+/// the previous declaration.
+pub struct Repl {
+    // the interner borrows the identifiers from the inputs, which are kept for the whole session
+    env: HandleMap<&'static str>,
+    evaluator: Evaluator,
+    output: Receiver<String>,
+    // the declarations and statements that were run
+    program: Vec<Toplevel>,
+    // the inputs that were run, each with its offset: like the modules of a program, all inputs
+    // share one offset space, so that spans in the session are unique
+    inputs: Vec<(usize, &'static str)>,
+    // the lines of unfinished input
+    pending: String,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        let (send, output) = channel();
+        let mut evaluator = Evaluator::new();
+        evaluator.output = Some(send);
+        Repl {
+            env: HandleMap::new(),
+            evaluator,
+            output,
+            program: Vec::new(),
+            inputs: Vec::new(),
+            pending: String::new(),
+        }
+    }
+
+    /// Whether the last line left the input unfinished, e.g. in the middle of a block.
+    pub fn is_continued(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Process a line of input and return the text to show, one line per printed value or error.
+    pub fn line(&mut self, line: &str) -> String {
+        if !self.is_continued() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                return self.command(command);
+            }
+        } else if line.trim().is_empty() {
+            let code = std::mem::take(&mut self.pending);
+            return match parse(&mut self.env.clone(), &code) {
+                Input::Invalid(errors) | Input::Incomplete(errors) => render(&code, &errors),
+                _ => String::new(),
+            };
+        }
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        let code = std::mem::take(&mut self.pending);
+        // parsed with a copy of the interner, which only borrows the input: the source is only
+        // kept once the input becomes part of the session
+        let mut env = self.env.clone();
+        match parse(&mut env, &code) {
+            Input::Program(program) => self.run(&env, &code, program),
+            Input::Exprs(exprs) => self.show(&env, &code, exprs),
+            Input::Incomplete(_) => {
+                self.pending = code;
+                String::new()
+            }
+            Input::Invalid(errors) => render(&code, &errors),
+        }
+    }
+
+    // The offset of the next input, behind the inputs so far.
+    fn base(&self) -> usize {
+        self.inputs
+            .last()
+            .map_or(0, |(base, code)| base + code.len() + 1)
+    }
+
+    // The session followed by `more`, in which redeclarations replace earlier declarations.
+    fn extend(&self, more: &[Toplevel]) -> Vec<Toplevel> {
+        let name = |toplevel: &Toplevel| match toplevel {
+            Toplevel::Declaration(Declaration::Function(name, ..))
+            | Toplevel::Declaration(Declaration::Struct(name, _)) => Some(name.node),
+            _ => None,
+        };
+        let redeclared: Vec<_> = more.iter().filter_map(name).collect();
+        let mut program: Vec<_> = self
+            .program
+            .iter()
+            .filter(|toplevel| !matches!(name(toplevel), Some(name) if redeclared.contains(&name)))
+            .cloned()
+            .collect();
+        program.extend_from_slice(more);
+        program
+    }
+
+    // Moves the spans of a new input behind the inputs so far and checks it with the session.
+    fn check(
+        &self,
+        env: &HandleMap<&str>,
+        code: &str,
+        more: &mut [Toplevel],
+    ) -> Result<(), String> {
+        let base = self.base();
+        for toplevel in more.iter_mut() {
+            toplevel.offset(base);
+        }
+        module::reject_imports(more).map_err(|err| self.render(&[err], Some(code)))?;
+        typeck::check(env, &self.extend(more))
+            .map(|_| ())
+            .map_err(|errors| self.render(&errors, Some(code)))
+    }
+
+    // Declarations take effect before the statements of the same input are run, as in a program.
+    fn run(&mut self, env: &HandleMap<&str>, code: &str, mut program: Vec<Toplevel>) -> String {
+        if let Err(errors) = self.check(env, code, &mut program) {
+            return errors;
+        }
+        // the interner of the session borrows the identifiers from the source, which is therefore
+        // kept for good; parsed again, the input gets the same handles as with the copy
+        let code: &'static str = Box::leak(code.to_string().into_boxed_str());
+        parse(&mut self.env, code);
+        self.inputs.push((self.base(), code));
+        self.program = self.extend(&program);
+        self.program.truncate(self.program.len() - program.len());
+        for toplevel in &program {
+            if let Toplevel::Declaration(decl) = toplevel {
+                // imports were rejected by the check
+                self.evaluator.declare(decl).unwrap();
+                self.program.push(toplevel.clone());
+            }
+        }
+        let mut out = String::new();
+        for toplevel in program {
+            if let Toplevel::Stmt(stmt) = &toplevel {
+                let res = self.evaluator.execute(stmt);
+                out.extend(self.output.try_iter().map(|line| line + "\n"));
+                if let Err(err) = res {
+                    out.push_str(&self.render(&[err], None));
+                    break;
+                }
+                self.program.push(toplevel);
+            }
+        }
+        out
+    }
+
+    // Shows the values of expressions, which are type checked like a `print` of them. Unlike
+    // statements, they do not become part of the session.
+    fn show(&mut self, env: &HandleMap<&str>, code: &str, exprs: Vec<Expr>) -> String {
+        let span = Span::new(0, code.len());
+        let mut print = [Toplevel::Stmt(Stmt {
+            node: StmtKind::Print(exprs),
+            span,
+        })];
+        if let Err(errors) = self.check(env, code, &mut print) {
+            return errors;
+        }
+        let exprs = match &print[0] {
+            Toplevel::Stmt(Stmt {
+                node: StmtKind::Print(exprs),
+                ..
+            }) => exprs,
+            _ => unreachable!(),
+        };
+        let mut out = String::new();
+        for expr in exprs {
+            match self.evaluator.evaluate(expr) {
+                Ok(value) => writeln!(out, "{}", value).unwrap(),
+                Err(err) => {
+                    out.push_str(&self.render(&[err], Some(code)));
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    fn command(&mut self, command: &str) -> String {
+        let (name, code) = command.split_at(command.find(' ').unwrap_or(command.len()));
+        let mut env = self.env.clone();
+        match name {
+            "ast" => match parse(&mut env, code) {
+                Input::Program(program) => program.iter().map(|t| format!("{:?}\n", t)).collect(),
+                Input::Exprs(exprs) => exprs.iter().map(|e| format!("{:?}\n", e)).collect(),
+                Input::Invalid(errors) | Input::Incomplete(errors) => render(code, &errors),
+            },
+            "asm" | "bc" => {
+                let mut more = match parse(&mut env, code) {
+                    Input::Program(more) => more,
+                    Input::Exprs(_) => {
+                        return "expressions cannot be compiled, use `print`\n".to_string()
+                    }
+                    Input::Invalid(errors) | Input::Incomplete(errors) => {
+                        return render(code, &errors)
+                    }
+                };
+                let base = self.base();
+                for toplevel in &mut more {
+                    toplevel.offset(base);
+                }
+                let sections = match Compiler::new().compile(&env, &self.extend(&more)) {
+                    Ok(sections) => sections,
+                    Err(err) => return self.render(&[err], Some(code)),
+                };
+                if name == "asm" {
+                    let mut out = Vec::new();
+                    for section in &sections {
+                        section.print_lines(&mut out);
+                    }
+                    return String::from_utf8(out).unwrap();
+                }
+                match asm::assemble(&sections) {
+                    Ok(program) => program.to_string(),
+                    Err(err) => format!("{}\n", err),
+                }
+            }
+            "help" => HELP.to_string(),
+            _ => format!("unknown command `:{}`, see `:help`\n", name),
+        }
+    }
+
+    // Renders errors of the session, against the input their span points into. `current` is an
+    // input at the next offset that is not part of the session.
+    fn render(&self, errors: &[Error], current: Option<&str>) -> String {
+        let current = current.map(|code| (self.base(), code));
+        let mut out = String::new();
+        for err in errors {
+            let input = err.span().and_then(|span| {
+                self.inputs
+                    .iter()
+                    .copied()
+                    .chain(current)
+                    .find(|(base, code)| (*base..=base + code.len()).contains(&span.start))
+                    .map(|(base, code)| (span, base, code))
+            });
+            match input {
+                Some((span, base, code)) => {
+                    let span = Span::new(span.start - base, span.end - base);
+                    out += &SourceFile::new("<repl>", code).render_span(&err.to_string(), span);
+                }
+                None => out += &format!("{}\n", err),
+            }
+        }
+        out
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Renders errors of a single input, e.g. syntax errors.
+fn render(code: &str, errors: &[Error]) -> String {
+    let source = SourceFile::new("<repl>", code);
+    errors.iter().map(|err| source.render(err)).collect()
+}
+
+// Parses the code as program, or else as expressions.
+fn parse<'a>(env: &mut HandleMap<&'a str>, code: &'a str) -> Input {
+    let mut recovered = Vec::new();
+    let program = lang1::ProgramParser::new().parse(env, &mut recovered, code);
+    let mut errors: Vec<_> = recovered.into_iter().map(|e| e.error).collect();
+    match program {
+        Ok(program) if errors.is_empty() => return Input::Program(program),
+        Ok(_) => (),
+        Err(err) => errors.push(err),
+    }
+
+    let mut recovered = Vec::new();
+    let exprs = lang1::ExprsParser::new().parse(env, &mut recovered, code);
+    let mut expr_errors: Vec<_> = recovered.into_iter().map(|e| e.error).collect();
+    match exprs {
+        Ok(exprs) if expr_errors.is_empty() && !exprs.is_empty() => return Input::Exprs(exprs),
+        Ok(_) => (),
+        Err(err) => expr_errors.push(err),
+    }
+
+    // report the errors of the parser that got further
+    let start = |errors: &[ParseError<usize, _, _>]| {
+        errors
+            .first()
+            .and_then(|err| Error::from(err.clone()).span())
+    };
+    let errors = match (start(&errors), start(&expr_errors)) {
+        (Some(a), Some(b)) if b.start > a.start => expr_errors,
+        _ => errors,
+    };
+    let incomplete = errors
+        .iter()
+        .any(|err| matches!(err, ParseError::UnrecognizedEOF { .. }));
+    let errors = errors.into_iter().map(Error::from).collect();
+    if incomplete {
+        Input::Incomplete(errors)
+    } else {
+        Input::Invalid(errors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Repl;
+
+    fn session(lines: &[&str]) -> Vec<String> {
+        let mut repl = Repl::new();
+        lines.iter().map(|line| repl.line(line)).collect()
+    }
+
+    #[test]
+    fn persistent_state() {
+        assert_eq!(
+            session(&[
+                "let a = 20;",
+                "fn double(x) { return 2 * x; }",
+                "double(a) + 2, \"s\"",
+                "a += 1; print a;",
+                "[a, a]",
+            ]),
+            ["", "", "42\ns\n", "21\n", "[21, 21]\n"]
+        );
+    }
+
+    #[test]
+    fn continued_input() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.line("fn f(x) {"), "");
+        assert!(repl.is_continued());
+        assert_eq!(repl.line("  return x + 1;"), "");
+        assert_eq!(repl.line("}"), "");
+        assert!(!repl.is_continued());
+        assert_eq!(repl.line("f(1)"), "2\n");
+
+        // an empty line cancels unfinished input
+        assert_eq!(repl.line("print (1"), "");
+        assert!(repl
+            .line("")
+            .starts_with("parse error: unexpected end of file"));
+        assert!(!repl.is_continued());
+    }
+
+    #[test]
+    fn errors() {
+        let out = session(&[
+            "print 1, x;",
+            "1 +* 2;",
+            "let a = 1;",
+            "let a = true;",
+            "a",
+            "import \"util.l1\"; a = 2;",
+            "a",
+        ]);
+        assert_eq!(
+            out[0],
+            "resolve error: unknown identifier `x`\n --> <repl>:1:10\n  |\n1 | print 1, x;\n  |          ^\n"
+        );
+        assert!(out[1].starts_with("parse error: unexpected token `*`"));
+        assert!(out[1].contains(" --> <repl>:1:4\n  |\n1 | 1 +* 2;\n  |    ^\n"));
+        assert!(out[3].starts_with("type error: cannot bind `bool` to global `a` of type `int`"));
+        assert_eq!(out[4], "1\n");
+        assert!(out[5].starts_with("module error: cannot import `util.l1` into a single source"));
+        assert!(out[5].ends_with(
+            " --> <repl>:1:8\n  |\n1 | import \"util.l1\"; a = 2;\n  |        ^^^^^^^^^\n"
+        ));
+        assert_eq!(out[6], "1\n");
+    }
+
+    #[test]
+    fn redeclaration() {
+        assert_eq!(
+            session(&["fn f() { return 1; }", "fn f() { return \"one\"; }", "f()",]),
+            ["", "", "one\n"]
+        );
+    }
+
+    #[test]
+    fn kept_inputs() {
+        // only the source of inputs that were run is kept
+        let mut repl = Repl::new();
+        for line in &["let a = 1;", "fn f(x) {", "  return x + a;", "}"] {
+            repl.line(line);
+        }
+        for line in &[
+            "a + f(1)",
+            "print b;",
+            "1 +* 2",
+            "print (1",
+            "",
+            ":ast x",
+            ":asm print a;",
+        ] {
+            repl.line(line);
+        }
+        assert_eq!(repl.inputs.len(), 2);
+        assert_eq!(repl.line("f(a) + 1"), "3\n");
+        assert_eq!(
+            repl.line("print a, c;"),
+            "resolve error: unknown identifier `c`\n --> <repl>:1:10\n  |\n1 | print a, c;\n  |          ^\n"
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.line(":ast 1 + 2"), "(1 + 2)\n");
+        assert_eq!(repl.line("let a = 1;"), "");
+        assert_eq!(
+            repl.line(":asm print a;"),
+            "section .const\nsection .code\nentry:\n    push 1\n    storeg 0\n    loadg 0\n    output #0\n"
+        );
+        assert_eq!(
+            repl.line(":bc"),
+            "0     PushImmediate(1)\n1     StoreGlobal(0)\n2     Noop\n"
+        );
+        assert!(repl.line(":nope").starts_with("unknown command `:nope`"));
+    }
+}
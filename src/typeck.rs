@@ -0,0 +1,1332 @@
+use crate::{
+    ast::{
+        Declaration, Expr, ExprKind, Ident, Opcode, Span, SpannedIdent, Stmt, StmtKind, Toplevel,
+        Type, UnOp,
+    },
+    error::Error,
+};
+use handy::HandleMap;
+use std::collections::{HashMap, HashSet};
+
+/// Inferred signature of a function. Argument types are `None` if they are never constrained.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionType {
+    pub args: Vec<Option<Type>>,
+    pub ret: Option<Type>,
+}
+
+/// Results of the type checker, to be used by later passes.
+#[derive(Debug, Default)]
+pub struct TypeInfo {
+    /// The type of every checked expression, keyed by its span.
+    pub exprs: HashMap<Span, Type>,
+    pub functions: HashMap<Ident, FunctionType>,
+    /// The offset of the field of every field access, keyed by the span of the field name.
+    pub fields: HashMap<Span, usize>,
+    /// The bindings each anonymous function captures, in order of their first use, keyed by the
+    /// span of the function expression.
+    pub captures: HashMap<Span, Vec<Ident>>,
+}
+
+impl TypeInfo {
+    pub fn expr_type(&self, expr: &Expr) -> Option<Type> {
+        self.exprs.get(&expr.span).cloned()
+    }
+}
+
+// Type of an expression during inference. Variables are bound by unification; the element type of
+// an array is always a variable, so that partially known arrays can be refined. Structs refer to
+// their declaration by index, function types to their signature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Int,
+    Bool,
+    Str,
+    Array(usize),
+    Struct(usize),
+    Function(usize),
+    Var(usize),
+}
+
+// The fields of a struct are untyped in the declaration, their types are inferred like the
+// arguments of functions.
+struct StructDef {
+    name: Ident,
+    fields: Vec<(Ident, Ty)>,
+}
+
+#[derive(Clone)]
+struct Signature {
+    args: Vec<Ty>,
+    ret: Ty,
+}
+
+// A field access on a value whose struct is not known yet, with the type the access got.
+struct FieldAccess {
+    record: Ty,
+    record_span: Span,
+    field: SpannedIdent,
+    ty: Ty,
+}
+
+// An anonymous function being checked: bindings from scopes below `depth` are captured.
+struct Closure {
+    depth: usize,
+    captures: Vec<Ident>,
+}
+
+/// Check a parsed program: resolve identifiers, check the number of arguments of calls and
+/// infer the types of all expressions. Function argument and return types are inferred from their
+/// uses; there are no generic functions, so all calls of a function have to agree on the types.
+///
+/// `let` bindings directly at the toplevel are globals, visible in all functions. A global keeps
+/// its type if it is bound again.
+///
+/// Field accesses are resolved by the type of the accessed value. If that is not known yet, the
+/// struct is inferred from the field name if it belongs to exactly one struct, and otherwise from
+/// the other uses of the value (like the arguments a function is called with) once all types are
+/// inferred.
+///
+/// All errors are reported, ordered by their location.
+pub fn check(
+    env: &HandleMap<&str>,
+    program: &[Toplevel],
+) -> std::result::Result<TypeInfo, Vec<Error>> {
+    let mut checker = Checker {
+        env,
+        vars: Vec::new(),
+        scopes: Vec::new(),
+        globals: HashMap::new(),
+        defined: HashSet::new(),
+        functions: HashMap::new(),
+        signatures: Vec::new(),
+        closures: Vec::new(),
+        captures: Vec::new(),
+        structs: Vec::new(),
+        struct_names: HashMap::new(),
+        exprs: Vec::new(),
+        fields: Vec::new(),
+        ret: None,
+        loop_depth: 0,
+        lengths: Vec::new(),
+        field_accesses: Vec::new(),
+        errors: Vec::new(),
+    };
+    for toplevel in program {
+        match toplevel {
+            Toplevel::Declaration(Declaration::Function(name, args, _)) => {
+                checker.declare(name, args)
+            }
+            Toplevel::Declaration(Declaration::Struct(name, fields)) => {
+                checker.declare_struct(name, fields)
+            }
+            Toplevel::Stmt(Stmt {
+                node: StmtKind::LetBinding(ident, _),
+                ..
+            }) => {
+                if !checker.globals.contains_key(&ident.node) {
+                    let ty = checker.fresh();
+                    checker.globals.insert(ident.node, ty);
+                }
+            }
+            Toplevel::Stmt(_) | Toplevel::Declaration(Declaration::Import(_)) => (),
+        }
+    }
+    for toplevel in program {
+        if let Toplevel::Declaration(Declaration::Function(name, args, body)) = toplevel {
+            checker.check_function(name, args, body);
+        }
+    }
+    for toplevel in program {
+        if let Toplevel::Stmt(stmt) = toplevel {
+            checker.check_stmt(stmt);
+        }
+    }
+    checker.finish()
+}
+
+struct Checker<'env> {
+    env: &'env HandleMap<&'env str>,
+    // bindings of the type variables
+    vars: Vec<Option<Ty>>,
+    // local scopes, empty at the toplevel
+    scopes: Vec<HashMap<Ident, Ty>>,
+    globals: HashMap<Ident, Ty>,
+    // globals bound so far by the toplevel statements
+    defined: HashSet<Ident>,
+    // signatures of the declared functions
+    functions: HashMap<Ident, usize>,
+    signatures: Vec<Signature>,
+    // enclosing anonymous functions, innermost last
+    closures: Vec<Closure>,
+    captures: Vec<(Span, Vec<Ident>)>,
+    structs: Vec<StructDef>,
+    struct_names: HashMap<Ident, usize>,
+    exprs: Vec<(Span, Ty)>,
+    // field offsets of field accesses, keyed by the span of the field name
+    fields: Vec<(Span, usize)>,
+    // return type of the function being checked
+    ret: Option<Ty>,
+    loop_depth: usize,
+    // operands of `len`, checked once all types are inferred
+    lengths: Vec<(Ty, Span)>,
+    // field accesses on values of an unknown struct, resolved once all types are inferred
+    field_accesses: Vec<FieldAccess>,
+    errors: Vec<Error>,
+}
+
+impl<'env> Checker<'env> {
+    fn finish(mut self) -> std::result::Result<TypeInfo, Vec<Error>> {
+        self.resolve_field_accesses();
+        for (ty, span) in std::mem::take(&mut self.lengths) {
+            if let Ty::Int | Ty::Bool | Ty::Struct(_) | Ty::Function(_) = self.resolve(ty) {
+                let msg = format!(
+                    "operand of `len` must be a string or an array, found `{}`",
+                    self.show(ty)
+                );
+                self.errors.push(Error::Type(msg, span));
+            }
+        }
+        if !self.errors.is_empty() {
+            self.errors
+                .sort_by_key(|err| err.span().map(|span| span.start));
+            return Err(self.errors);
+        }
+        let mut info = TypeInfo::default();
+        for (span, ty) in &self.exprs {
+            if let Some(ty) = self.known(*ty) {
+                info.exprs.insert(*span, ty);
+            }
+        }
+        for (name, index) in &self.functions {
+            let signature = &self.signatures[*index];
+            let function = FunctionType {
+                args: signature.args.iter().map(|ty| self.known(*ty)).collect(),
+                ret: self.known(signature.ret),
+            };
+            info.functions.insert(*name, function);
+        }
+        info.fields = self.fields.into_iter().collect();
+        info.captures = self.captures.into_iter().collect();
+        Ok(info)
+    }
+
+    fn name(&self, ident: Ident) -> &'env str {
+        self.env.get(ident).unwrap()
+    }
+
+    fn fresh_var(&mut self) -> usize {
+        self.vars.push(None);
+        self.vars.len() - 1
+    }
+
+    fn fresh(&mut self) -> Ty {
+        Ty::Var(self.fresh_var())
+    }
+
+    fn resolve(&self, ty: Ty) -> Ty {
+        match ty {
+            Ty::Var(v) => match self.vars[v] {
+                Some(bound) => self.resolve(bound),
+                None => ty,
+            },
+            _ => ty,
+        }
+    }
+
+    fn known(&self, ty: Ty) -> Option<Type> {
+        match self.resolve(ty) {
+            Ty::Int => Some(Type::Int),
+            Ty::Bool => Some(Type::Bool),
+            Ty::Str => Some(Type::Str),
+            Ty::Array(element) => Some(Type::Array(Box::new(self.known(Ty::Var(element))?))),
+            Ty::Struct(index) => Some(Type::Struct(
+                self.name(self.structs[index].name).to_string(),
+            )),
+            Ty::Function(index) => {
+                let signature = &self.signatures[index];
+                let args: Option<Vec<_>> =
+                    signature.args.iter().map(|ty| self.known(*ty)).collect();
+                Some(Type::Function(args?, Box::new(self.known(signature.ret)?)))
+            }
+            Ty::Var(_) => None,
+        }
+    }
+
+    // Type for error messages, `_` stands for unknown types.
+    fn show(&self, ty: Ty) -> String {
+        match self.resolve(ty) {
+            Ty::Array(element) => format!("[{}]", self.show(Ty::Var(element))),
+            Ty::Function(index) => {
+                let signature = &self.signatures[index];
+                let args: Vec<_> = signature.args.iter().map(|ty| self.show(*ty)).collect();
+                format!("fn({}) -> {}", args.join(", "), self.show(signature.ret))
+            }
+            Ty::Var(_) => "_".into(),
+            ty => self.known(ty).unwrap().to_string(),
+        }
+    }
+
+    fn occurs(&self, v: usize, ty: Ty) -> bool {
+        match self.resolve(ty) {
+            Ty::Var(w) => v == w,
+            Ty::Array(element) => self.occurs(v, Ty::Var(element)),
+            Ty::Function(index) => {
+                let signature = &self.signatures[index];
+                signature.args.iter().any(|ty| self.occurs(v, *ty)) || self.occurs(v, signature.ret)
+            }
+            _ => false,
+        }
+    }
+
+    // On a mismatch, returns the (expected, found) types.
+    fn unify(&mut self, expected: Ty, found: Ty) -> std::result::Result<(), (String, String)> {
+        let mismatch = |checker: &Self| Err((checker.show(expected), checker.show(found)));
+        match (self.resolve(expected), self.resolve(found)) {
+            (Ty::Var(a), Ty::Var(b)) if a == b => Ok(()),
+            (Ty::Var(v), ty) | (ty, Ty::Var(v)) => {
+                if self.occurs(v, ty) {
+                    return mismatch(self);
+                }
+                self.vars[v] = Some(ty);
+                Ok(())
+            }
+            (Ty::Array(a), Ty::Array(b)) => match self.unify(Ty::Var(a), Ty::Var(b)) {
+                Ok(()) => Ok(()),
+                Err(_) => mismatch(self),
+            },
+            (Ty::Function(a), Ty::Function(b)) if a != b => {
+                let a = self.signatures[a].clone();
+                let b = self.signatures[b].clone();
+                if a.args.len() != b.args.len() {
+                    return mismatch(self);
+                }
+                let pairs = a.args.iter().zip(&b.args).chain(Some((&a.ret, &b.ret)));
+                for (a, b) in pairs {
+                    if self.unify(*a, *b).is_err() {
+                        return mismatch(self);
+                    }
+                }
+                Ok(())
+            }
+            (a, b) if a == b => Ok(()),
+            _ => mismatch(self),
+        }
+    }
+
+    fn expect(&mut self, expr: &Expr, expected: Ty, what: &str) {
+        let found = self.check_expr(expr);
+        self.require(found, expected, what, expr.span);
+    }
+
+    fn require(&mut self, found: Ty, expected: Ty, what: &str, span: Span) {
+        if let Err((expected, found)) = self.unify(expected, found) {
+            self.errors.push(Error::Type(
+                format!("{} must be `{}`, found `{}`", what, expected, found),
+                span,
+            ));
+        }
+    }
+
+    // `+` adds integers or concatenates strings; a string on either side decides.
+    fn addition_type(&self, a: Ty, b: Ty) -> Ty {
+        if self.resolve(a) == Ty::Str || self.resolve(b) == Ty::Str {
+            Ty::Str
+        } else {
+            Ty::Int
+        }
+    }
+
+    fn unknown_identifier(&mut self, ident: Ident, span: Span) {
+        let msg = format!("unknown identifier `{}`", self.name(ident));
+        self.errors.push(Error::Resolve(msg, span));
+    }
+
+    fn declare_struct(&mut self, name: &SpannedIdent, fields: &[SpannedIdent]) {
+        if self.struct_names.contains_key(&name.node) {
+            let msg = format!(
+                "struct `{}` is defined more than once",
+                self.name(name.node)
+            );
+            self.errors.push(Error::Resolve(msg, name.span));
+            return;
+        }
+        let mut def = StructDef {
+            name: name.node,
+            fields: Vec::new(),
+        };
+        for field in fields {
+            if def.fields.iter().any(|(ident, _)| *ident == field.node) {
+                let msg = format!(
+                    "field `{}` is declared more than once",
+                    self.name(field.node)
+                );
+                self.errors.push(Error::Resolve(msg, field.span));
+                continue;
+            }
+            def.fields.push((field.node, self.fresh()));
+        }
+        self.struct_names.insert(name.node, self.structs.len());
+        self.structs.push(def);
+    }
+
+    fn declare(&mut self, name: &SpannedIdent, args: &[SpannedIdent]) {
+        self.declare_args(args);
+        if self.functions.contains_key(&name.node) {
+            let msg = format!(
+                "function `{}` is defined more than once",
+                self.name(name.node)
+            );
+            self.errors.push(Error::Resolve(msg, name.span));
+            return;
+        }
+        let signature = self.fresh_signature(args.len());
+        self.functions.insert(name.node, signature);
+    }
+
+    // Only the last of several arguments with the same name would be visible in the body.
+    fn declare_args(&mut self, args: &[SpannedIdent]) {
+        for (i, arg) in args.iter().enumerate() {
+            if args[..i].iter().any(|other| other.node == arg.node) {
+                let msg = format!(
+                    "argument `{}` is declared more than once",
+                    self.name(arg.node)
+                );
+                self.errors.push(Error::Resolve(msg, arg.span));
+            }
+        }
+    }
+
+    // Returns the index of a new signature with unknown types.
+    fn fresh_signature(&mut self, num_args: usize) -> usize {
+        let signature = Signature {
+            args: (0..num_args).map(|_| self.fresh()).collect(),
+            ret: self.fresh(),
+        };
+        self.signatures.push(signature);
+        self.signatures.len() - 1
+    }
+
+    fn check_function(&mut self, name: &SpannedIdent, args: &[SpannedIdent], body: &Stmt) {
+        let signature = &self.signatures[self.functions[&name.node]];
+        let ret = signature.ret;
+        let scope = args
+            .iter()
+            .zip(&signature.args)
+            .map(|(arg, ty)| (arg.node, *ty))
+            .collect();
+        self.scopes = vec![scope];
+        self.ret = Some(ret);
+        self.check_stmt(body);
+        if !always_returns(body) {
+            // falling off the end returns 0
+            if let Err((expected, _)) = self.unify(ret, Ty::Int) {
+                let msg = format!(
+                    "function `{}` returns `{}`, but may end without a return",
+                    self.name(name.node),
+                    expected
+                );
+                self.errors.push(Error::Type(msg, name.span));
+            }
+        }
+        self.ret = None;
+        self.scopes.clear();
+    }
+
+    // Local bindings from outside of the anonymous functions being checked are recorded as
+    // captures. Functions see all globals, the toplevel only the ones already bound.
+    fn lookup(&mut self, ident: Ident) -> Option<Ty> {
+        let local = self
+            .scopes
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(depth, scope)| Some((depth, *scope.get(&ident)?)));
+        let (depth, ty) = match local {
+            Some(local) => local,
+            None if self.ret.is_none() && !self.defined.contains(&ident) => return None,
+            None => return self.globals.get(&ident).copied(),
+        };
+        for closure in self.closures.iter_mut().rev() {
+            if depth >= closure.depth {
+                break;
+            }
+            if !closure.captures.contains(&ident) {
+                closure.captures.push(ident);
+            }
+        }
+        Some(ty)
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.node {
+            StmtKind::LetBinding(ident, expr) => {
+                let ty = self.check_expr(expr);
+                match self.scopes.last_mut() {
+                    Some(scope) => {
+                        scope.insert(ident.node, ty);
+                    }
+                    None => {
+                        let global = self.globals[&ident.node];
+                        if let Err((expected, found)) = self.unify(global, ty) {
+                            let msg = format!(
+                                "cannot bind `{}` to global `{}` of type `{}`",
+                                found,
+                                self.name(ident.node),
+                                expected
+                            );
+                            self.errors.push(Error::Type(msg, expr.span));
+                        }
+                        self.defined.insert(ident.node);
+                    }
+                }
+            }
+            StmtKind::Assign(ident, expr, op) => {
+                let binding = match self.lookup(ident.node) {
+                    Some(ty) => ty,
+                    None => {
+                        self.unknown_identifier(ident.node, ident.span);
+                        self.fresh()
+                    }
+                };
+                if let Some(op) = op {
+                    let what = format!("operand of `{:?}=`", op);
+                    let found = self.check_expr(expr);
+                    let ty = match op {
+                        Opcode::Add => self.addition_type(binding, found),
+                        _ => Ty::Int,
+                    };
+                    self.require(binding, ty, &what, ident.span);
+                    self.require(found, ty, &what, expr.span);
+                } else {
+                    let found = self.check_expr(expr);
+                    if let Err((expected, found)) = self.unify(binding, found) {
+                        let msg = format!(
+                            "cannot assign `{}` to `{}` of type `{}`",
+                            found,
+                            self.name(ident.node),
+                            expected
+                        );
+                        self.errors.push(Error::Type(msg, expr.span));
+                    }
+                }
+            }
+            StmtKind::AssignIndex(array, index, expr, op) => {
+                let element = self.check_index(array, index);
+                let found = self.check_expr(expr);
+                match op {
+                    Some(op) => {
+                        let what = format!("operand of `{:?}=`", op);
+                        let ty = match op {
+                            Opcode::Add => self.addition_type(element, found),
+                            _ => Ty::Int,
+                        };
+                        self.require(element, ty, &what, array.span.to(index.span));
+                        self.require(found, ty, &what, expr.span);
+                    }
+                    None => self.require(found, element, "array element", expr.span),
+                }
+            }
+            StmtKind::AssignField(record, field, expr, op) => {
+                let ty = self.check_field(record, field);
+                let found = self.check_expr(expr);
+                match op {
+                    Some(op) => {
+                        let what = format!("operand of `{:?}=`", op);
+                        let expected = match op {
+                            Opcode::Add => self.addition_type(ty, found),
+                            _ => Ty::Int,
+                        };
+                        self.require(ty, expected, &what, record.span.to(field.span));
+                        self.require(found, expected, &what, expr.span);
+                    }
+                    None => {
+                        let what = format!("field `{}`", self.name(field.node));
+                        self.require(found, ty, &what, expr.span);
+                    }
+                }
+            }
+            StmtKind::Print(exprs) => {
+                for e in exprs {
+                    self.check_expr(e);
+                }
+            }
+            StmtKind::IfElse(expr, if_stmt, else_stmt) => {
+                self.expect(expr, Ty::Bool, "condition");
+                self.check_stmt(if_stmt);
+                if let Some(else_stmt) = else_stmt {
+                    self.check_stmt(else_stmt);
+                }
+            }
+            StmtKind::While(expr, body) => {
+                self.expect(expr, Ty::Bool, "condition");
+                self.check_loop_body(body);
+            }
+            StmtKind::For(ident, start, end, body) => {
+                self.expect(start, Ty::Int, "start of range");
+                self.expect(end, Ty::Int, "end of range");
+                let mut scope = HashMap::new();
+                scope.insert(ident.node, Ty::Int);
+                self.scopes.push(scope);
+                self.check_loop_body(body);
+                self.scopes.pop();
+            }
+            StmtKind::Break | StmtKind::Continue if self.loop_depth == 0 => {
+                let what = match stmt.node {
+                    StmtKind::Break => "break",
+                    _ => "continue",
+                };
+                let msg = format!("{} outside of loop", what);
+                self.errors.push(Error::Resolve(msg, stmt.span));
+            }
+            StmtKind::Break | StmtKind::Continue => (),
+            StmtKind::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                for s in stmts {
+                    self.check_stmt(s);
+                }
+                self.scopes.pop();
+            }
+            StmtKind::Call(expr) => {
+                self.check_expr(expr);
+            }
+            StmtKind::Return(expr) => {
+                let found = self.check_expr(expr);
+                match self.ret {
+                    Some(ret) => {
+                        if let Err((expected, found)) = self.unify(ret, found) {
+                            let msg =
+                                format!("return value must be `{}`, found `{}`", expected, found);
+                            self.errors.push(Error::Type(msg, expr.span));
+                        }
+                    }
+                    None => self.errors.push(Error::Resolve(
+                        "return outside of function".into(),
+                        stmt.span,
+                    )),
+                }
+            }
+        }
+    }
+
+    fn check_loop_body(&mut self, body: &Stmt) {
+        self.loop_depth += 1;
+        self.check_stmt(body);
+        self.loop_depth -= 1;
+    }
+
+    fn check_expr(&mut self, expr: &Expr) -> Ty {
+        let ty = match &expr.node {
+            ExprKind::Number(_) => Ty::Int,
+            ExprKind::Bool(_) => Ty::Bool,
+            ExprKind::Str(_) => Ty::Str,
+            ExprKind::Array(elements) => {
+                let element = self.fresh_var();
+                for e in elements {
+                    self.expect(e, Ty::Var(element), "array element");
+                }
+                Ty::Array(element)
+            }
+            ExprKind::Index(array, index) => self.check_index(array, index),
+            ExprKind::Struct(name, fields) => self.check_struct(name, fields),
+            ExprKind::Field(record, field) => self.check_field(record, field),
+            ExprKind::EnvLoad(ident) => match self.lookup(*ident) {
+                Some(ty) => ty,
+                None => match self.functions.get(ident) {
+                    Some(index) => Ty::Function(*index),
+                    None => {
+                        self.unknown_identifier(*ident, expr.span);
+                        self.fresh()
+                    }
+                },
+            },
+            ExprKind::Op(a, op @ (Opcode::And | Opcode::Or), b) => {
+                let what = format!("operand of `{:?}`", op);
+                self.expect(a, Ty::Bool, &what);
+                self.expect(b, Ty::Bool, &what);
+                Ty::Bool
+            }
+            ExprKind::Op(a, op @ (Opcode::Equal | Opcode::NotEqual), b) => {
+                let ta = self.check_expr(a);
+                let tb = self.check_expr(b);
+                if let Err((ta, tb)) = self.unify(ta, tb) {
+                    let msg = format!(
+                        "operands of `{:?}` must have the same type, found `{}` and `{}`",
+                        op, ta, tb
+                    );
+                    self.errors.push(Error::Type(msg, expr.span));
+                }
+                Ty::Bool
+            }
+            ExprKind::Op(a, Opcode::Add, b) => {
+                let ta = self.check_expr(a);
+                let tb = self.check_expr(b);
+                let ty = self.addition_type(ta, tb);
+                self.require(ta, ty, "operand of `+`", a.span);
+                self.require(tb, ty, "operand of `+`", b.span);
+                ty
+            }
+            ExprKind::Op(a, op, b) => {
+                let what = format!("operand of `{:?}`", op);
+                self.expect(a, Ty::Int, &what);
+                self.expect(b, Ty::Int, &what);
+                match op {
+                    Opcode::LessThan
+                    | Opcode::LessEqual
+                    | Opcode::GreaterThan
+                    | Opcode::GreaterEqual => Ty::Bool,
+                    _ => Ty::Int,
+                }
+            }
+            ExprKind::Unary(UnOp::Len, e) => {
+                let ty = self.check_expr(e);
+                self.lengths.push((ty, e.span));
+                Ty::Int
+            }
+            ExprKind::Unary(op, e) => {
+                let (ty, symbol) = match op {
+                    UnOp::Not => (Ty::Bool, "not"),
+                    UnOp::Neg => (Ty::Int, "-"),
+                    UnOp::Complement => (Ty::Int, "~"),
+                    UnOp::Len => unreachable!("handled above"),
+                };
+                self.expect(e, ty, &format!("operand of `{}`", symbol));
+                ty
+            }
+            ExprKind::Call(name, exprs) => self.check_call(expr, name, exprs),
+            ExprKind::Function(args, body) => self.check_closure(expr, args, body),
+            ExprKind::Error => {
+                self.errors
+                    .push(Error::Parse("invalid expression".into(), Some(expr.span)));
+                self.fresh()
+            }
+        };
+        self.exprs.push((expr.span, ty));
+        ty
+    }
+
+    // Returns the element type.
+    fn check_index(&mut self, array: &Expr, index: &Expr) -> Ty {
+        let element = self.fresh_var();
+        self.expect(array, Ty::Array(element), "indexed value");
+        self.expect(index, Ty::Int, "array index");
+        Ty::Var(element)
+    }
+
+    fn check_struct(&mut self, name: &SpannedIdent, fields: &[(SpannedIdent, Expr)]) -> Ty {
+        let index = match self.struct_names.get(&name.node) {
+            Some(index) => *index,
+            None => {
+                let msg = format!("unknown struct `{}`", self.name(name.node));
+                self.errors.push(Error::Resolve(msg, name.span));
+                for (_, e) in fields {
+                    self.check_expr(e);
+                }
+                return self.fresh();
+            }
+        };
+        let mut given = Vec::new();
+        for (field, e) in fields {
+            let field_name = self.name(field.node);
+            let declared = self.structs[index]
+                .fields
+                .iter()
+                .find(|(ident, _)| *ident == field.node)
+                .map(|(_, ty)| *ty);
+            let msg = match declared {
+                None => format!(
+                    "struct `{}` has no field `{}`",
+                    self.name(name.node),
+                    field_name
+                ),
+                Some(_) if given.contains(&field.node) => {
+                    format!("field `{}` is given more than once", field_name)
+                }
+                Some(ty) => {
+                    given.push(field.node);
+                    self.expect(e, ty, &format!("field `{}`", field_name));
+                    continue;
+                }
+            };
+            self.errors.push(Error::Resolve(msg, field.span));
+            self.check_expr(e);
+        }
+        let missing: Vec<_> = self.structs[index]
+            .fields
+            .iter()
+            .filter(|(ident, _)| !given.contains(ident))
+            .map(|(ident, _)| *ident)
+            .collect();
+        for ident in missing {
+            let msg = format!(
+                "missing field `{}` in `{}`",
+                self.name(ident),
+                self.name(name.node)
+            );
+            self.errors.push(Error::Resolve(msg, name.span));
+        }
+        Ty::Struct(index)
+    }
+
+    // Returns the field type and records the offset of the field. If the struct of the value is
+    // not known yet and more than one struct has the field, the access is resolved in `finish`.
+    fn check_field(&mut self, record: &Expr, field: &SpannedIdent) -> Ty {
+        let ty = self.check_expr(record);
+        match self.resolve(ty) {
+            Ty::Var(_) => {
+                let candidates: Vec<usize> = (0..self.structs.len())
+                    .filter(|index| {
+                        let def = &self.structs[*index];
+                        def.fields.iter().any(|(ident, _)| *ident == field.node)
+                    })
+                    .collect();
+                match candidates[..] {
+                    [index] => {
+                        self.require(ty, Ty::Struct(index), "record", record.span);
+                        self.struct_field(index, field)
+                    }
+                    [] => {
+                        let msg = format!("no struct has a field `{}`", self.name(field.node));
+                        self.errors.push(Error::Resolve(msg, field.span));
+                        self.fresh()
+                    }
+                    _ => {
+                        let field_ty = self.fresh();
+                        self.field_accesses.push(FieldAccess {
+                            record: ty,
+                            record_span: record.span,
+                            field: *field,
+                            ty: field_ty,
+                        });
+                        field_ty
+                    }
+                }
+            }
+            _ => self.known_field(ty, record.span, field),
+        }
+    }
+
+    // Field access on a value whose type is not a variable.
+    fn known_field(&mut self, ty: Ty, record_span: Span, field: &SpannedIdent) -> Ty {
+        match self.resolve(ty) {
+            Ty::Struct(index) => self.struct_field(index, field),
+            _ => {
+                let msg = format!(
+                    "value with field `{}` must be a struct, found `{}`",
+                    self.name(field.node),
+                    self.show(ty)
+                );
+                self.errors.push(Error::Type(msg, record_span));
+                self.fresh()
+            }
+        }
+    }
+
+    fn struct_field(&mut self, index: usize, field: &SpannedIdent) -> Ty {
+        let def = &self.structs[index];
+        match def
+            .fields
+            .iter()
+            .position(|(ident, _)| *ident == field.node)
+        {
+            Some(offset) => {
+                self.fields.push((field.span, offset));
+                def.fields[offset].1
+            }
+            None => {
+                let msg = format!(
+                    "struct `{}` has no field `{}`",
+                    self.name(def.name),
+                    self.name(field.node)
+                );
+                self.errors.push(Error::Resolve(msg, field.span));
+                self.fresh()
+            }
+        }
+    }
+
+    // Resolves the deferred field accesses whose struct has become known, until no more can be.
+    // Resolving one can decide the struct of another (`p.a.x`), hence the repetition.
+    fn resolve_field_accesses(&mut self) {
+        let mut pending = std::mem::take(&mut self.field_accesses);
+        loop {
+            let before = pending.len();
+            let mut unresolved = Vec::new();
+            for access in pending {
+                if let Ty::Var(_) = self.resolve(access.record) {
+                    unresolved.push(access);
+                    continue;
+                }
+                let ty = self.known_field(access.record, access.record_span, &access.field);
+                let what = format!("field `{}`", self.name(access.field.node));
+                self.require(access.ty, ty, &what, access.field.span);
+            }
+            pending = unresolved;
+            if pending.len() == before {
+                break;
+            }
+        }
+        for access in pending {
+            let msg = format!(
+                "cannot infer the struct of a value with field `{}`",
+                self.name(access.field.node)
+            );
+            self.errors.push(Error::Type(msg, access.record_span));
+        }
+    }
+
+    fn check_closure(&mut self, expr: &Expr, args: &[SpannedIdent], body: &Stmt) -> Ty {
+        self.declare_args(args);
+        let index = self.fresh_signature(args.len());
+        let signature = self.signatures[index].clone();
+        let scope = args
+            .iter()
+            .zip(&signature.args)
+            .map(|(arg, ty)| (arg.node, *ty))
+            .collect();
+        self.closures.push(Closure {
+            depth: self.scopes.len(),
+            captures: Vec::new(),
+        });
+        self.scopes.push(scope);
+        let outer_ret = self.ret.replace(signature.ret);
+        let outer_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        self.check_stmt(body);
+        if !always_returns(body) {
+            if let Err((expected, _)) = self.unify(signature.ret, Ty::Int) {
+                let msg = format!(
+                    "function returns `{}`, but may end without a return",
+                    expected
+                );
+                self.errors.push(Error::Type(msg, expr.span));
+            }
+        }
+        self.ret = outer_ret;
+        self.loop_depth = outer_loop_depth;
+        self.scopes.pop();
+        let closure = self.closures.pop().unwrap();
+        self.captures.push((expr.span, closure.captures));
+        Ty::Function(index)
+    }
+
+    fn check_call(&mut self, expr: &Expr, name: &SpannedIdent, exprs: &[Expr]) -> Ty {
+        // local bindings shadow declared functions
+        let index = match self.lookup(name.node) {
+            Some(ty) => match self.resolve(ty) {
+                Ty::Function(index) => Some(index),
+                Ty::Var(_) => {
+                    let index = self.fresh_signature(exprs.len());
+                    self.require(ty, Ty::Function(index), "called value", name.span);
+                    Some(index)
+                }
+                _ => {
+                    let msg = format!(
+                        "called value `{}` must be a function, found `{}`",
+                        self.name(name.node),
+                        self.show(ty)
+                    );
+                    self.errors.push(Error::Type(msg, name.span));
+                    None
+                }
+            },
+            None => match self.functions.get(&name.node) {
+                Some(index) => Some(*index),
+                None => {
+                    self.unknown_identifier(name.node, name.span);
+                    None
+                }
+            },
+        };
+        let (args, ret) = match index {
+            Some(index) => {
+                let signature = &self.signatures[index];
+                (signature.args.clone(), signature.ret)
+            }
+            None => {
+                for e in exprs {
+                    self.check_expr(e);
+                }
+                return self.fresh();
+            }
+        };
+        if args.len() != exprs.len() {
+            let msg = format!(
+                "function `{}` expects {} arguments, found {}",
+                self.name(name.node),
+                args.len(),
+                exprs.len()
+            );
+            self.errors.push(Error::Resolve(msg, expr.span));
+        }
+        for (i, e) in exprs.iter().enumerate() {
+            let found = self.check_expr(e);
+            if let Some(arg) = args.get(i) {
+                if let Err((expected, found)) = self.unify(*arg, found) {
+                    let msg = format!(
+                        "argument {} of `{}` must be `{}`, found `{}`",
+                        i + 1,
+                        self.name(name.node),
+                        expected,
+                        found
+                    );
+                    self.errors.push(Error::Type(msg, e.span));
+                }
+            }
+        }
+        ret
+    }
+}
+
+// Conservative check whether a statement returns on all paths.
+fn always_returns(stmt: &Stmt) -> bool {
+    match &stmt.node {
+        StmtKind::Return(_) => true,
+        StmtKind::Block(stmts) => stmts.iter().any(always_returns),
+        StmtKind::IfElse(_, if_stmt, Some(else_stmt)) => {
+            always_returns(if_stmt) && always_returns(else_stmt)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, FunctionType};
+    use crate::ast::Type;
+    use crate::compile::Compiler;
+    use handy::HandleMap;
+
+    fn errors(code: &str) -> Vec<String> {
+        let mut env = HandleMap::new();
+        let program = Compiler::new().parse(&mut env, code).unwrap();
+        match check(&env, &program) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn inference() {
+        let code = "
+            fn id(x) { return x; }
+            fn is_small(n) { return n < limit(); }
+            fn limit() { return 10; }
+            fn unused(a) { }
+            fn first(a) { return a[0]; }
+            print first([]) + \"!\";
+            let b = id(true);
+            print is_small(3);
+        ";
+        let mut env = HandleMap::new();
+        let program = Compiler::new().parse(&mut env, code).unwrap();
+        let info = check(&env, &program).unwrap();
+        let signature = |name: &str| info.functions[&env.find_handle(&name).unwrap()].clone();
+        assert_eq!(
+            signature("id"),
+            FunctionType {
+                args: vec![Some(Type::Bool)],
+                ret: Some(Type::Bool)
+            }
+        );
+        assert_eq!(
+            signature("is_small"),
+            FunctionType {
+                args: vec![Some(Type::Int)],
+                ret: Some(Type::Bool)
+            }
+        );
+        assert_eq!(
+            signature("first"),
+            FunctionType {
+                args: vec![Some(Type::Array(Box::new(Type::Str)))],
+                ret: Some(Type::Str)
+            }
+        );
+        assert_eq!(
+            signature("unused"),
+            FunctionType {
+                args: vec![None],
+                ret: Some(Type::Int)
+            }
+        );
+    }
+
+    #[test]
+    fn resolve_errors() {
+        assert_eq!(
+            errors("fn f(a, b, c) { return a; } print f(1, 2); print x;"),
+            [
+                "resolve error: function `f` expects 3 arguments, found 2",
+                "resolve error: unknown identifier `x`"
+            ]
+        );
+        assert_eq!(
+            errors("fn f() { } fn f() { } break; return 1;"),
+            [
+                "resolve error: function `f` is defined more than once",
+                "resolve error: break outside of loop",
+                "resolve error: return outside of function"
+            ]
+        );
+        assert_eq!(
+            errors("fn f(a, b, a) { return a; } let g = fn(x, x) { return x; }; print f(1, 2, 3), g(1, 2);"),
+            [
+                "resolve error: argument `a` is declared more than once",
+                "resolve error: argument `x` is declared more than once"
+            ]
+        );
+    }
+
+    #[test]
+    fn type_errors() {
+        assert_eq!(
+            errors("fn f(x) { return x + 1; } print f(true); if f(1) { }"),
+            [
+                "type error: argument 1 of `f` must be `int`, found `bool`",
+                "type error: condition must be `bool`, found `int`"
+            ]
+        );
+        assert_eq!(
+            errors("fn f(x) { if x { return 1; } return false; }"),
+            ["type error: return value must be `int`, found `bool`"]
+        );
+        assert_eq!(
+            errors("fn f(x) { if x { return true; } }"),
+            ["type error: function `f` returns `bool`, but may end without a return"]
+        );
+        assert_eq!(
+            errors("let a = 1; print a == true, -false, a + (a < 2);"),
+            [
+                "type error: operands of `==` must have the same type, found `int` and `bool`",
+                "type error: operand of `-` must be `int`, found `bool`",
+                "type error: operand of `+` must be `int`, found `bool`"
+            ]
+        );
+        assert_eq!(
+            errors(r#"let s = "a"; s += 1; print len(s) + s, len(2), s - 1;"#),
+            [
+                "type error: operand of `+=` must be `string`, found `int`",
+                "type error: operand of `+` must be `string`, found `int`",
+                "type error: operand of `len` must be a string or an array, found `int`",
+                "type error: operand of `-` must be `int`, found `string`"
+            ]
+        );
+    }
+
+    #[test]
+    fn array_errors() {
+        assert_eq!(
+            errors("let a = [1, true]; a[0] = false; print a[true], len(a) + 1, 5[0];"),
+            [
+                "type error: array element must be `int`, found `bool`",
+                "type error: array element must be `int`, found `bool`",
+                "type error: array index must be `int`, found `bool`",
+                "type error: indexed value must be `[_]`, found `int`"
+            ]
+        );
+        assert_eq!(
+            errors("let a = [[1]]; let b = []; a = [b, [true]]; b[0] = b; let c = []; c[0] = c;"),
+            [
+                "type error: cannot assign `[[bool]]` to `a` of type `[[int]]`",
+                "type error: array element must be `bool`, found `[bool]`",
+                "type error: array element must be `_`, found `[_]`"
+            ]
+        );
+    }
+
+    #[test]
+    fn structs() {
+        let code = "
+            struct Point { x, y }
+            struct Named { name, point }
+            fn norm(p) { return p.x * p.x + p.y * p.y; }
+            fn name(n) { return n.name; }
+            let n = Named { point: Point { x: 1, y: 2 }, name: \"n\" };
+            n.point.x += norm(n.point);
+            print name(n);
+        ";
+        let mut env = HandleMap::new();
+        let program = Compiler::new().parse(&mut env, code).unwrap();
+        let info = check(&env, &program).unwrap();
+        let signature = |name: &str| info.functions[&env.find_handle(&name).unwrap()].clone();
+        assert_eq!(
+            signature("norm"),
+            FunctionType {
+                args: vec![Some(Type::Struct("Point".into()))],
+                ret: Some(Type::Int)
+            }
+        );
+        assert_eq!(
+            signature("name"),
+            FunctionType {
+                args: vec![Some(Type::Struct("Named".into()))],
+                ret: Some(Type::Str)
+            }
+        );
+        let mut offsets: Vec<_> = info.fields.values().copied().collect();
+        offsets.sort_unstable();
+        assert_eq!(offsets, [0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn deferred_fields() {
+        // both structs have `x` and `inner`, the calls decide
+        let code = "
+            struct P { x, inner }
+            struct Q { inner, x }
+            fn get(p) { return p.x; }
+            fn nested(q) { return q.inner.x; }
+            print get(Q { inner: 1, x: true }), nested(P { x: 0, inner: Q { inner: 2, x: false } });
+        ";
+        let mut env = HandleMap::new();
+        let program = Compiler::new().parse(&mut env, code).unwrap();
+        let info = check(&env, &program).unwrap();
+        let signature = |name: &str| info.functions[&env.find_handle(&name).unwrap()].clone();
+        assert_eq!(
+            signature("get"),
+            FunctionType {
+                args: vec![Some(Type::Struct("Q".into()))],
+                ret: Some(Type::Bool)
+            }
+        );
+        assert_eq!(
+            signature("nested"),
+            FunctionType {
+                args: vec![Some(Type::Struct("P".into()))],
+                ret: Some(Type::Bool)
+            }
+        );
+        let mut offsets: Vec<_> = info.fields.values().copied().collect();
+        offsets.sort_unstable();
+        assert_eq!(offsets, [1, 1, 1]);
+    }
+
+    #[test]
+    fn closures() {
+        let code = "
+            fn apply(f, x) { return f(x); }
+            fn compose(f, g) { return fn(x) { return g(f(x)); }; }
+            let limit = 3;
+            let small = fn(n) { return n < limit; };
+            print apply(small, 1), compose(small, fn(b) { return not b; });
+        ";
+        let mut env = HandleMap::new();
+        let program = Compiler::new().parse(&mut env, code).unwrap();
+        let info = check(&env, &program).unwrap();
+        let signature = |name: &str| info.functions[&env.find_handle(&name).unwrap()].clone();
+        let predicate = Type::Function(vec![Type::Int], Box::new(Type::Bool));
+        assert_eq!(
+            signature("apply"),
+            FunctionType {
+                args: vec![Some(predicate.clone()), Some(Type::Int)],
+                ret: Some(Type::Bool)
+            }
+        );
+        assert_eq!(
+            signature("compose").ret.unwrap().to_string(),
+            "fn(int) -> bool"
+        );
+        let mut captures: Vec<_> = info
+            .captures
+            .values()
+            .map(|captures| {
+                let names: Vec<_> = captures.iter().map(|c| *env.get(*c).unwrap()).collect();
+                names.join(",")
+            })
+            .collect();
+        captures.sort();
+        assert_eq!(captures, ["", "", "g,f"]);
+    }
+
+    #[test]
+    fn closure_errors() {
+        assert_eq!(
+            errors(
+                "let a = 1; print a(2); let f = fn(x) { return x; }; print f(1, 2), f(true) + 1;"
+            ),
+            [
+                "type error: called value `a` must be a function, found `int`",
+                "resolve error: function `f` expects 1 arguments, found 2",
+                "type error: argument 1 of `f` must be `int`, found `bool`"
+            ]
+        );
+        assert_eq!(
+            errors("fn g(x) { return x; } let f = g; f = fn(a, b) { }; f = fn(s) { return s + \"\"; }; print g(1);"),
+            [
+                "type error: cannot assign `fn(_, _) -> int` to `f` of type `fn(_) -> _`",
+                "type error: argument 1 of `g` must be `string`, found `int`"
+            ]
+        );
+        assert_eq!(
+            errors("while true { let f = fn() { break; }; } let h = fn() { if true { return true; } };"),
+            [
+                "resolve error: break outside of loop",
+                "type error: function returns `bool`, but may end without a return"
+            ]
+        );
+    }
+
+    #[test]
+    fn global_errors() {
+        assert_eq!(
+            errors("fn f() { return g + 1; } print x; let x = 1; let x = \"s\"; let g = true;"),
+            [
+                "resolve error: unknown identifier `x`",
+                "type error: cannot bind `string` to global `x` of type `int`",
+                "type error: cannot bind `bool` to global `g` of type `int`"
+            ]
+        );
+    }
+
+    #[test]
+    fn struct_errors() {
+        assert_eq!(
+            errors("struct P { x, x } struct P { y } print Q { }, P { x: 1, z: 2, x: 3 }.z;"),
+            [
+                "resolve error: field `x` is declared more than once",
+                "resolve error: struct `P` is defined more than once",
+                "resolve error: unknown struct `Q`",
+                "resolve error: struct `P` has no field `z`",
+                "resolve error: field `x` is given more than once",
+                "resolve error: struct `P` has no field `z`"
+            ]
+        );
+        assert_eq!(
+            errors("struct P { x, y } struct Q { x } fn f(a) { return a.x; } let p = P { y: 1 };"),
+            [
+                "type error: cannot infer the struct of a value with field `x`",
+                "resolve error: missing field `x` in `P`"
+            ]
+        );
+        assert_eq!(
+            errors("struct P { x } struct Q { x } fn f(a) { return a.x; } print f(1), f(Q { x: 1 }).x;"),
+            [
+                "type error: value with field `x` must be a struct, found `int`",
+                "type error: cannot infer the struct of a value with field `x`",
+                "type error: argument 1 of `f` must be `int`, found `Q`"
+            ]
+        );
+        assert_eq!(
+            errors("struct P { x } let p = P { x: 1 }; p.x = true; print p.y, 1.x, p.x.x; fn f(a) { return a.y; }"),
+            [
+                "type error: field `x` must be `int`, found `bool`",
+                "resolve error: struct `P` has no field `y`",
+                "type error: value with field `x` must be a struct, found `int`",
+                "type error: value with field `x` must be a struct, found `int`",
+                "resolve error: no struct has a field `y`"
+            ]
+        );
+    }
+
+    #[test]
+    fn error_expressions() {
+        let mut env = HandleMap::new();
+        let mut errors = Vec::new();
+        let program = crate::lang1::ProgramParser::new()
+            .parse(&mut env, &mut errors, "print 1 * + 2;")
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        let errors = check(&env, &program).err().unwrap();
+        assert_eq!(errors[0].to_string(), "parse error: invalid expression");
+    }
+}
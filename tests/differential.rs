@@ -0,0 +1,122 @@
+//! Runs every `data/*.l1` program through both backends (`eval::Evaluator` and compiler + `bytecode::Vm`)
+//! and compares their output with each other and with the golden file `data/*.out` next to the program.
+//!
+//! Set `L1_BLESS=1` to (re)write the golden files from the VM output.
+
+use handy::HandleMap;
+use lalrpop_test::{
+    bytecode::{IoChannels, Vm},
+    compile::Compiler,
+    error::Result,
+    eval::Evaluator,
+    typeck,
+};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+
+fn run_vm(code: &str) -> Result<Vec<String>> {
+    let prog = Compiler::new().build(code)?;
+    let (send, recv) = channel();
+    let mut io = IoChannels::new();
+    io.channels.push(send);
+    let mut vm = Vm::from_program(prog);
+    vm.exec(Some(&io))?;
+    Ok(recv.try_iter().map(|v| v.to_string()).collect())
+}
+
+fn run_eval(code: &str) -> Result<Vec<String>> {
+    let mut env = HandleMap::new();
+    let program = Compiler::new()
+        .parse(&mut env, code)
+        .map_err(|mut errors| errors.remove(0))?;
+    // the same front end as the compiler, which checks the program before generating code
+    typeck::check(&env, &program).map_err(|mut errors| errors.remove(0))?;
+    let (send, recv) = channel();
+    let mut evaluator = Evaluator::new();
+    evaluator.output = Some(send);
+    // deeply recursive programs like `test_ack` need more than the default, the threads running
+    // the programs have the stack for it
+    evaluator.max_depth = 50_000;
+    evaluator.run(&program)?;
+    Ok(recv.try_iter().map(|v| v.to_string()).collect())
+}
+
+fn diff(left_name: &str, left: &[String], right_name: &str, right: &[String]) -> Option<String> {
+    if left == right {
+        return None;
+    }
+    let mut out = format!("--- {}\n+++ {}\n", left_name, right_name);
+    for i in 0..left.len().max(right.len()) {
+        match (left.get(i), right.get(i)) {
+            (Some(l), Some(r)) if l == r => out += &format!(" {}\n", l),
+            (l, r) => {
+                if let Some(l) = l {
+                    out += &format!("-{}\n", l);
+                }
+                if let Some(r) = r {
+                    out += &format!("+{}\n", r);
+                }
+            }
+        }
+    }
+    Some(out)
+}
+
+fn check(path: &Path) -> Option<String> {
+    let code = std::fs::read_to_string(path).unwrap();
+    let vm_out = match run_vm(&code) {
+        Ok(out) => out,
+        Err(err) => return Some(format!("vm: {}", err)),
+    };
+    let eval_out = match run_eval(&code) {
+        Ok(out) => out,
+        Err(err) => return Some(format!("eval: {}", err)),
+    };
+    if let Some(d) = diff("vm", &vm_out, "eval", &eval_out) {
+        return Some(d);
+    }
+
+    let golden_path = path.with_extension("out");
+    if std::env::var_os("L1_BLESS").is_some() {
+        let mut golden = vm_out.join("\n");
+        golden.push('\n');
+        std::fs::write(&golden_path, golden).unwrap();
+        return None;
+    }
+    let golden: Vec<String> = match std::fs::read_to_string(&golden_path) {
+        Ok(golden) => golden.lines().map(String::from).collect(),
+        Err(err) => return Some(format!("{}: {}", golden_path.display(), err)),
+    };
+    diff("expected", &golden, "vm", &vm_out)
+}
+
+fn programs() -> Vec<PathBuf> {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("data");
+    let mut programs: Vec<PathBuf> = std::fs::read_dir(data)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("l1"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+#[test]
+fn differential() {
+    // one thread per program; the evaluator recurses on the native stack, so give deeply
+    // recursive programs some room (see `max_depth` in `run_eval`)
+    let handles: Vec<_> = programs()
+        .into_iter()
+        .map(|path| {
+            std::thread::Builder::new()
+                .stack_size(256 * 1024 * 1024)
+                .spawn(move || check(&path).map(|f| format!("{}:\n{}", path.display(), f)))
+                .unwrap()
+        })
+        .collect();
+    let failures: Vec<String> = handles
+        .into_iter()
+        .filter_map(|handle| handle.join().unwrap())
+        .collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,118 @@
+//! Runs the `l1` driver binary on the programs in `data/` and on small programs written to a
+//! temporary directory.
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+fn l1(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_l1"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("l1-driver-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn run_stages() {
+    let expected = std::fs::read_to_string("data/test_factorial.out").unwrap();
+    assert_eq!(stdout(&l1(&["run", "data/test_factorial.l1"])), expected);
+
+    // every stage that can be run gives the same output
+    let dir = temp_dir("stages");
+    for (emit, file) in &[("asm", "f.xas"), ("yaml", "f.yaml"), ("bin", "f.l1b")] {
+        let file = dir.join(file);
+        let emit = format!("--emit={}", emit);
+        stdout(&l1(&[
+            "build",
+            &emit,
+            "-o",
+            path(&file),
+            "data/test_factorial.l1",
+        ]));
+        assert_eq!(stdout(&l1(&["run", path(&file)])), expected);
+    }
+    let listing = stdout(&l1(&["asm", "--emit=bytecode", path(&dir.join("f.xas"))])).to_string();
+    assert_eq!(
+        stdout(&l1(&[
+            "disasm",
+            "--emit=bytecode",
+            path(&dir.join("f.l1b"))
+        ])),
+        listing
+    );
+
+    // the disassembly assembles back to the same bytecode
+    let xas = dir.join("g.xas");
+    stdout(&l1(&["disasm", "-o", path(&xas), path(&dir.join("f.l1b"))]));
+    assert_eq!(
+        stdout(&l1(&["asm", "--emit=bytecode", path(&xas)])),
+        listing
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn imports_and_errors() {
+    let dir = temp_dir("imports");
+    std::fs::write(
+        dir.join("main.l1"),
+        "import \"util.l1\";\nprint util::f(1);\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("util.l1"), "fn f(x) {\n  return x + true;\n}\n").unwrap();
+    let output = l1(&["check", path(&dir.join("main.l1"))]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("type error: operand of `+` must be `int`, found `bool`\n"));
+    assert!(stderr.contains("util.l1:2:14"), "{}", stderr);
+
+    std::fs::write(dir.join("util.l1"), "fn f(x) { return x + 1; }\n").unwrap();
+    assert_eq!(stdout(&l1(&["check", path(&dir.join("main.l1"))])), "");
+    assert_eq!(stdout(&l1(&["run", path(&dir.join("main.l1"))])), "2\n");
+    std::fs::remove_dir_all(dir).unwrap();
+
+    let output = l1(&["build", "--emit=nope", "data/test_factorial.l1"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn run_streams_output() {
+    // the program never ends, its output has to show up while it runs
+    let dir = temp_dir("stream");
+    let file = dir.join("loop.l1");
+    std::fs::write(
+        &file,
+        "let i = 0;\nwhile true {\n  print i;\n  i += 1;\n}\n",
+    )
+    .unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_l1"))
+        .args(["run", path(&file)])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "0");
+    assert_eq!(lines.next().unwrap().unwrap(), "1");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}